@@ -0,0 +1,194 @@
+use prometheus::core::{MetricVec, MetricVecBuilder};
+use prometheus::{Counter, CounterVec, GaugeVec};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+/// A metric vec that tracks at most `limit` distinct label combinations.
+///
+/// Once the limit is reached, new combinations are counted in `exceeded` and get the detached
+/// `discarded` metric that isn't exported, so callers can keep updating it unconditionally.
+///
+/// When the vec was registered without one of the labels callers pass, `skip_label` is the
+/// index of the value to drop.
+///
+/// Clones share the series and the known combinations.
+pub struct BoundedVec<T: MetricVecBuilder> {
+    vec: MetricVec<T>,
+    skip_label: Option<usize>,
+    limit: Option<usize>,
+    known: Arc<Mutex<HashSet<Vec<String>>>>,
+    exceeded: Counter,
+    discarded: T::M,
+}
+
+pub type BoundedGaugeVec = BoundedVec<<GaugeVec as MetricVecKind>::Builder>;
+pub type BoundedCounterVec = BoundedVec<<CounterVec as MetricVecKind>::Builder>;
+
+/// Names the builder of a metric vec type, since prometheus doesn't export the builders.
+pub trait MetricVecKind {
+    type Builder: MetricVecBuilder;
+}
+
+impl<T: MetricVecBuilder> MetricVecKind for MetricVec<T> {
+    type Builder = T;
+}
+
+impl<T: MetricVecBuilder> BoundedVec<T> {
+    pub fn new(
+        vec: MetricVec<T>,
+        skip_label: Option<usize>,
+        limit: Option<usize>,
+        exceeded: Counter,
+        discarded: T::M,
+    ) -> Self {
+        Self {
+            vec,
+            skip_label,
+            limit,
+            known: Arc::new(Mutex::new(HashSet::new())),
+            exceeded,
+            discarded,
+        }
+    }
+
+    fn label_values<'a>(&self, values: &[&'a str]) -> Vec<&'a str> {
+        values
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| Some(*i) != self.skip_label)
+            .map(|(_, v)| *v)
+            .collect()
+    }
+
+    pub fn with_label_values(&self, values: &[&str]) -> T::M {
+        let values = self.label_values(values);
+        let values = values.as_slice();
+        let values_key: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        let mut known = self.known.lock().unwrap();
+        if !known.contains(&values_key) {
+            if self.limit.is_some_and(|limit| known.len() >= limit) {
+                self.exceeded.inc();
+                return self.discarded.clone();
+            }
+            known.insert(values_key);
+        }
+        self.vec.with_label_values(values)
+    }
+
+    pub fn reset(&self) {
+        self.known.lock().unwrap().clear();
+        self.vec.reset();
+    }
+
+    /// Removes the label combinations that don't start with one of `prefixes`, which are given
+    /// with all labels like the values of `with_label_values`.
+    pub fn retain_prefixes(&self, prefixes: &[Vec<&str>]) {
+        let prefixes: HashSet<Vec<&str>> = prefixes.iter().map(|p| self.label_values(p)).collect();
+        let mut known = self.known.lock().unwrap();
+        known.retain(|values| {
+            let values: Vec<&str> = values.iter().map(String::as_str).collect();
+            let kept = prefixes
+                .iter()
+                .any(|prefix| values.len() >= prefix.len() && values[..prefix.len()] == prefix[..]);
+            if !kept {
+                let _ = self.vec.remove_label_values(&values);
+            }
+            kept
+        });
+    }
+}
+
+impl<T: MetricVecBuilder> Clone for BoundedVec<T> {
+    fn clone(&self) -> Self {
+        Self {
+            vec: self.vec.clone(),
+            skip_label: self.skip_label,
+            limit: self.limit,
+            known: self.known.clone(),
+            exceeded: self.exceeded.clone(),
+            discarded: self.discarded.clone(),
+        }
+    }
+}
+
+/// The per-container vecs of both kinds, to drop the series of removed containers.
+pub enum ContainerVec {
+    Gauge(BoundedGaugeVec),
+    Counter(BoundedCounterVec),
+}
+
+impl ContainerVec {
+    pub fn retain_prefixes(&self, prefixes: &[Vec<&str>]) {
+        match self {
+            Self::Gauge(vec) => vec.retain_prefixes(prefixes),
+            Self::Counter(vec) => vec.retain_prefixes(prefixes),
+        }
+    }
+}
+
+impl BoundedCounterVec {
+    /// Counters can only go up, so the series follows a cumulative value by increments. A value
+    /// below the counter (e.g. a recreated container) starts a new series.
+    pub fn set_total(&self, values: &[&str], total: f64) {
+        let mut counter = self.with_label_values(values);
+        if total < counter.get() {
+            let _ = self.vec.remove_label_values(&self.label_values(values));
+            counter = self.with_label_values(values);
+        }
+        let delta = total - counter.get();
+        if delta > 0.0 {
+            counter.inc_by(delta);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::core::Collector;
+    use prometheus::{Gauge, Opts};
+
+    fn gauge_vec(skip_label: Option<usize>, limit: Option<usize>) -> (BoundedGaugeVec, Counter) {
+        let labels: &[&str] = match skip_label {
+            Some(_) => &["container", "device"],
+            None => &["pod", "container", "device"],
+        };
+        let vec = GaugeVec::new(Opts::new("test", "test"), labels).unwrap();
+        let exceeded = Counter::new("exceeded", "exceeded").unwrap();
+        let discarded = Gauge::new("discarded", "discarded").unwrap();
+        let bounded = BoundedGaugeVec::new(vec, skip_label, limit, exceeded.clone(), discarded);
+        (bounded, exceeded)
+    }
+
+    fn series(vec: &BoundedGaugeVec) -> usize {
+        vec.vec.collect().iter().map(|f| f.get_metric().len()).sum()
+    }
+
+    #[test]
+    fn retain_prefixes_frees_the_limit() {
+        let (vec, exceeded) = gauge_vec(None, Some(2));
+        vec.with_label_values(&["web", "app", "sda"]).set(1.0);
+        vec.with_label_values(&["web", "db", "sda"]).set(1.0);
+        vec.with_label_values(&["web", "cache", "sda"]).set(1.0);
+        assert_eq!(exceeded.get(), 1.0);
+        assert_eq!(series(&vec), 2);
+
+        vec.retain_prefixes(&[vec!["web", "app"]]);
+        assert_eq!(series(&vec), 1);
+        vec.with_label_values(&["web", "cache", "sda"]).set(1.0);
+        assert_eq!(exceeded.get(), 1.0);
+        assert_eq!(series(&vec), 2);
+    }
+
+    #[test]
+    fn retain_prefixes_without_pod_label() {
+        let (vec, _) = gauge_vec(Some(0), None);
+        vec.with_label_values(&["web", "app", "sda"]).set(1.0);
+        vec.with_label_values(&["web", "app", "sdb"]).set(1.0);
+        vec.with_label_values(&["", "solo", "sda"]).set(1.0);
+        vec.retain_prefixes(&[vec!["web", "app"]]);
+        assert_eq!(series(&vec), 2);
+        vec.retain_prefixes(&[]);
+        assert_eq!(series(&vec), 0);
+    }
+}
@@ -1,19 +1,177 @@
+mod bounded;
+mod metrics;
+mod openmetrics;
+mod remote_write;
+mod ssh;
+mod systemd;
+mod tls;
+
 use anyhow::{anyhow, Result};
 use chrono::Utc;
-use clap::Parser;
+use clap::{ArgEnum, Parser};
+use flate2::{write::GzEncoder, Compression};
+use futures::stream::{self, Stream, StreamExt};
+use futures::Future;
 use hyper::{
-    header::CONTENT_TYPE,
+    header::{
+        HeaderMap, HeaderValue, ACCEPT, ACCEPT_ENCODING, CONNECTION, CONTENT_ENCODING, CONTENT_TYPE,
+    },
+    server::conn::AddrStream,
     service::{make_service_fn, service_fn},
-    Body, Request, Response, Server,
+    Body, Request, Response, Server, StatusCode,
 };
 use lazy_static::lazy_static;
-use podman_api::opts::ContainerListOpts;
-use podman_api::Podman;
-use prometheus::{register_gauge, register_gauge_vec, Encoder, Gauge, GaugeVec, TextEncoder};
+use listenfd::ListenFd;
+use metrics::{check_info_labels, sanitize_label_name, strict_name, Metrics, MetricsConfig};
+use openmetrics::{OpenMetricsEncoder, OPENMETRICS_FORMAT};
+use podman_api::models::{LibpodContainerInspectResponse, LibpodContainerStats, ListContainer};
+use podman_api::opts::{ContainerListOpts, EventsOpts};
+use podman_api::{models::Event, ApiVersion, Podman};
+use prometheus::proto::{LabelPair, Metric, MetricFamily};
+use prometheus::{Counter, Encoder, TextEncoder, TEXT_FORMAT};
+use regex::Regex;
+use remote_write::{RemoteWriteOptions, RemoteWriter};
 use serde_json::Value;
+use ssh::{SshOptions, SshTunnel};
 use std::collections::hash_map::HashMap;
-use std::net::IpAddr;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use systemd::SystemdClient;
+use tls::TlsOptions;
+use tokio::sync::{Mutex as AsyncMutex, Semaphore};
+use tokio::time;
+use tracing::{debug, error, info, warn, Subscriber};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{
+    fmt::{self, MakeWriter},
+    prelude::*,
+    registry::LookupSpan,
+    EnvFilter, Layer,
+};
+
+const DEFAULT_PODMAN_SOCKET: &str = "/run/podman/podman.sock";
+const COMPOSE_PROJECT_LABEL: &str = "com.docker.compose.project";
+const COMPOSE_SERVICE_LABEL: &str = "com.docker.compose.service";
+/// Set by `podman generate systemd --new` on the containers its units create.
+const SYSTEMD_UNIT_LABEL: &str = "PODMAN_SYSTEMD_UNIT";
+const CONTAINER_EVENTS: [&str; 5] = ["create", "start", "die", "died", "remove"];
+const SCRAPE_TIMEOUT_MARGIN: f64 = 0.5;
+const COLLECT_RETRY_DELAYS: [Duration; 3] = [
+    Duration::from_millis(100),
+    Duration::from_millis(200),
+    Duration::from_millis(400),
+];
+
+#[derive(Debug, Clone, PartialEq, ArgEnum)]
+enum MetricNaming {
+    Legacy,
+    Strict,
+}
+
+#[derive(Debug, Clone, ArgEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, ArgEnum)]
+enum LogRotation {
+    Minutely,
+    Hourly,
+    Daily,
+    Never,
+}
+
+/// Metric groups that can be selected with `--collector.enable-only`.
+#[derive(Debug, Clone, Copy, PartialEq, ArgEnum)]
+#[clap(rename_all = "snake_case")]
+enum MetricGroup {
+    ContainerStats,
+    ContainerState,
+    ContainerInfo,
+    PodInfo,
+    ImageInfo,
+    VolumeInfo,
+    NetworkInfo,
+    SecretInfo,
+    /// Only collected when selected explicitly, since it needs `--systemd`.
+    SystemdUnits,
+}
+
+/// Source of the `container` label: `name`, `label:<key>` or `hostname`.
+#[derive(Debug, Clone)]
+enum ContainerNameFrom {
+    Name,
+    Label(String),
+    Hostname,
+}
+
+impl FromStr for ContainerNameFrom {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(Self::Name),
+            "hostname" => Ok(Self::Hostname),
+            _ => match s.strip_prefix("label:") {
+                Some(key) if !key.is_empty() => Ok(Self::Label(key.to_string())),
+                _ => Err(format!("expected name, hostname or label:<key>, got {}", s)),
+            },
+        }
+    }
+}
+
+/// A static label added to all metrics, `<name>=<env var>` reads its value from the environment.
+#[derive(Debug, Clone)]
+struct ExternalLabel {
+    name: String,
+    value: String,
+}
+
+impl FromStr for ExternalLabel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, var) = s
+            .split_once('=')
+            .filter(|(name, var)| !name.is_empty() && !var.is_empty())
+            .ok_or_else(|| format!("expected <name>=<env var>, got {}", s))?;
+        if sanitize_label_name(name) != name {
+            return Err(format!("invalid label name {}", name));
+        }
+        let value = env::var(var).map_err(|e| format!("{}: {}", var, e))?;
+        Ok(Self {
+            name: name.to_string(),
+            value,
+        })
+    }
+}
+
+/// `host:port` as node_exporter's `--web.listen-address`, `:port` listens on all interfaces.
+fn parse_listen_address(value: &str) -> Result<SocketAddr> {
+    let value = match value.strip_prefix(':') {
+        Some(port) => format!("0.0.0.0:{}", port),
+        None => value.to_string(),
+    };
+    SocketAddr::from_str(&value).map_err(|e| anyhow!("Expected host:port, {}", e))
+}
+
+/// A number of seconds above zero, anything else couldn't bound a collection.
+fn parse_scrape_timeout(value: &str) -> Result<f64> {
+    let secs = f64::from_str(value).map_err(|e| anyhow!("Expected seconds, {}", e))?;
+    if !secs.is_finite() || secs <= 0.0 {
+        return Err(anyhow!("Expected seconds above zero, got {}", value));
+    }
+    Ok(secs)
+}
 
 #[derive(Debug, Parser)]
 struct AppArgs {
@@ -21,208 +179,1980 @@ struct AppArgs {
     host: String,
     #[clap(short, long, default_value = "9807")]
     port: u16,
-    #[clap(long, default_value = "unix:///run/podman/podman.sock")]
-    podman: String,
+    #[clap(long = "web.listen-address", parse(try_from_str = parse_listen_address))]
+    listen_address: Option<SocketAddr>,
+    #[clap(long, default_value = "15")]
+    http_keepalive: u64,
+    #[clap(long, default_value = "true", parse(try_from_str))]
+    tcp_nodelay: bool,
+    #[clap(long, default_value = "10")]
+    max_connections: usize,
+    #[clap(long)]
+    podman: Option<String>,
+    #[clap(long, alias = "ssh-identity")]
+    podman_ssh_identity: Option<PathBuf>,
+    #[clap(long)]
+    podman_ssh_passphrase_file: Option<PathBuf>,
+    #[clap(long)]
+    podman_tcp_tls_ca: Option<PathBuf>,
+    #[clap(long)]
+    podman_tcp_tls_cert: Option<PathBuf>,
+    #[clap(long)]
+    podman_tcp_tls_key: Option<PathBuf>,
+    #[clap(long, default_value = "3.0.0")]
+    min_api_version: ApiVersion,
+    #[clap(long, default_value = "name")]
+    container_name_from: ContainerNameFrom,
+    #[clap(long = "expose-label")]
+    expose_labels: Vec<String>,
+    #[clap(long = "expose-env", requires = "collect-env")]
+    expose_env: Vec<String>,
+    #[clap(long)]
+    collect_env: bool,
+    #[clap(long, requires = "dynamic-label-prefix")]
+    dynamic_labels: bool,
+    #[clap(long)]
+    dynamic_label_prefix: Option<String>,
+    #[clap(long = "external-labels")]
+    external_labels: Vec<ExternalLabel>,
+    #[clap(long)]
+    name_include: Option<Regex>,
+    #[clap(long)]
+    name_exclude: Option<Regex>,
+    #[clap(long)]
+    exclude_infra: bool,
+    #[clap(long = "ignore-pod")]
+    ignore_pods: Vec<String>,
+    #[clap(long)]
+    running_only: bool,
+    #[clap(long)]
+    full_image_id: bool,
+    #[clap(long)]
+    collect_security: bool,
+    #[clap(long)]
+    collect_sizes: bool,
+    #[clap(long)]
+    collect_interface_packets: bool,
+    #[clap(long)]
+    collect_fds: bool,
+    #[clap(long)]
+    collect_pod_stats: bool,
+    #[clap(long)]
+    collect_started_at: bool,
+    #[clap(long, requires = "event-driven")]
+    start_count_from_inspect: bool,
+    #[clap(long)]
+    collect_exited_only_once: bool,
+    #[clap(long, default_value = "8")]
+    inspect_concurrency: usize,
+    #[clap(long, default_value = "3")]
+    reconnect_after: usize,
+    #[clap(long = "collector.disable-container-stats")]
+    disable_container_stats: bool,
+    #[clap(long = "collector.enable-only", arg_enum)]
+    enable_only: Vec<MetricGroup>,
+    #[clap(long, requires = "enable-only")]
+    systemd: bool,
+    #[clap(long, default_value = "300")]
+    system_df_interval: u64,
+    #[clap(long)]
+    max_label_combinations: Option<usize>,
+    #[clap(long)]
+    no_pod_label: bool,
+    #[clap(long)]
+    event_driven: bool,
+    #[clap(long)]
+    print_once: bool,
+    #[clap(long)]
+    remote_write_url: Option<String>,
+    #[clap(long, default_value = "30")]
+    remote_write_interval: u64,
+    #[clap(long, default_value = "10")]
+    remote_write_timeout: u64,
+    #[clap(long, requires = "remote-write-url")]
+    remote_write_user: Option<String>,
+    #[clap(long, requires = "remote-write-user")]
+    remote_write_password: Option<String>,
+    #[clap(long, parse(try_from_str = parse_scrape_timeout))]
+    max_scrape_timeout: Option<f64>,
+    #[clap(long = "web.metrics-path", default_value = "/metrics")]
+    metrics_path: String,
+    #[clap(long)]
+    access_log: bool,
+    #[clap(long, arg_enum, default_value = "legacy")]
+    metric_naming: MetricNaming,
+    #[clap(long = "web.disable-exporter-metrics")]
+    disable_exporter_metrics: bool,
+    #[clap(long = "collector.disable-process-metrics")]
+    disable_process_metrics: bool,
+    #[clap(long, default_value = "15")]
+    poll_interval: u64,
+    #[clap(long, default_value = "info")]
+    log_level: String,
+    #[clap(long, arg_enum, default_value = "text")]
+    log_format: LogFormat,
+    #[clap(long)]
+    log_file: Option<PathBuf>,
+    #[clap(long, arg_enum, default_value = "daily")]
+    log_rotation: LogRotation,
+    #[clap(long)]
+    no_stderr: bool,
+}
+
+/// The test binary's arguments aren't the exporter's, so tests run with the defaults.
+fn parse_args() -> AppArgs {
+    if cfg!(test) {
+        AppArgs::parse_from(["exporter"])
+    } else {
+        AppArgs::parse()
+    }
 }
 
 lazy_static! {
-    static ref ARGS: AppArgs = AppArgs::parse();
-    static ref COLLECTOR: Collector = Collector::new(&ARGS.podman).unwrap();
-    static ref CONTAINER_TOTAL: Gauge =
-        register_gauge!("podman_container_total", "Total count of containers").unwrap();
-    static ref CONTAINER_COUNT: GaugeVec =
-        register_gauge_vec!("podman_container_count", "Count of containers", &["pod"],).unwrap();
-    static ref CONTAINER_STATE: GaugeVec = register_gauge_vec!(
-        "podman_container_state",
-        "Container current state (-1=unknown,0=exited/stopped,1=running,2=created)",
-        &["pod", "container"],
-    )
-    .unwrap();
-    static ref CONTAINER_UPTIME: GaugeVec = register_gauge_vec!(
-        "podman_container_uptime",
-        "Container uptime",
-        &["pod", "container"],
-    )
-    .unwrap();
-    static ref CONTAINER_UPTIME_CALC: GaugeVec = register_gauge_vec!(
-        "podman_container_uptime_calc",
-        "Container uptime (Calculated value)",
-        &["pod", "container"],
-    )
-    .unwrap();
-    static ref CONTAINER_SYSTEM_NANO: GaugeVec = register_gauge_vec!(
-        "podman_container_system_nano",
-        "Container system nano",
-        &["pod", "container"],
-    )
-    .unwrap();
-    static ref CONTAINER_PIDS: GaugeVec = register_gauge_vec!(
-        "podman_container_pids",
-        "Count of running pids in container",
-        &["pod", "container"],
-    )
-    .unwrap();
-    static ref CONTAINER_AVG_CPU: GaugeVec = register_gauge_vec!(
-        "podman_container_avg_cpu",
-        "Container Avg CPU usage",
-        &["pod", "container"],
-    )
-    .unwrap();
-    static ref CONTAINER_CPU: GaugeVec = register_gauge_vec!(
-        "podman_container_cpu",
-        "Container CPU usage",
-        &["pod", "container"],
-    )
-    .unwrap();
-    static ref CONTAINER_CPU_NANO: GaugeVec = register_gauge_vec!(
-        "podman_container_cpu_nano",
-        "Container CPU usage (nano)",
-        &["pod", "container"],
-    )
-    .unwrap();
-    static ref CONTAINER_CPU_SYSTEM_NANO: GaugeVec = register_gauge_vec!(
-        "podman_container_cpu_system_nano",
-        "Container CPU usage (system nano)",
-        &["pod", "container"],
-    )
-    .unwrap();
-    static ref CONTAINER_MEM_USAGE: GaugeVec = register_gauge_vec!(
-        "podman_container_mem_usage",
-        "Container memory usage (bytes)",
-        &["pod", "container"],
-    )
-    .unwrap();
-    static ref CONTAINER_MEM_LIMIT: GaugeVec = register_gauge_vec!(
-        "podman_container_mem_limit",
-        "Container memory limit",
-        &["pod", "container"],
-    )
-    .unwrap();
-    static ref CONTAINER_MEM_PERC: GaugeVec = register_gauge_vec!(
-        "podman_container_mem_perc",
-        "Container memory usage (percentage)",
-        &["pod", "container"],
-    )
-    .unwrap();
-    static ref CONTAINER_NET_INP: GaugeVec = register_gauge_vec!(
-        "podman_container_network_input",
-        "Container network input",
-        &["pod", "container"],
-    )
-    .unwrap();
-    static ref CONTAINER_NET_OUT: GaugeVec = register_gauge_vec!(
-        "podman_container_network_output",
-        "Container network output",
-        &["pod", "container"],
-    )
-    .unwrap();
-    static ref CONTAINER_BL_INP: GaugeVec = register_gauge_vec!(
-        "podman_container_block_input",
-        "Container block input",
-        &["pod", "container"],
-    )
-    .unwrap();
-    static ref CONTAINER_BL_OUT: GaugeVec = register_gauge_vec!(
-        "podman_container_block_output",
-        "Container block output",
-        &["pod", "container"],
-    )
-    .unwrap();
+    static ref ARGS: AppArgs = parse_args();
+    static ref PODMAN_URI: String = ARGS.podman.clone().unwrap_or_else(detect_podman_socket);
+    static ref HOST_CGROUP_VERSION: &'static str = fs::read_to_string("/proc/mounts")
+        .map(|mounts| host_cgroup_version(&mounts))
+        .unwrap_or("");
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 struct ContInfo {
     pod: Option<String>,
     name: String,
+    image: String,
+    image_id: String,
+    is_infra: bool,
+    labels: HashMap<String, String>,
+    env: HashMap<String, String>,
     state: isize,
+    /// The list `State` as reported, e.g. `exited`.
+    state_name: String,
     uptime: i64,
+    started_at: Option<i64>,
+    restart_count: Option<i64>,
+    mounts: usize,
+    volumes: Option<Vec<VolumeMount>>,
+    secrets: Option<Vec<SecretRef>>,
+    networks: Vec<String>,
+    network_mode: String,
+    user_namespace: String,
+    rw_size: Option<i64>,
+    rootfs_size: Option<i64>,
+    restart_policy: Option<RestartPolicy>,
+    ulimits: Vec<Ulimit>,
+    cpu_count: Option<usize>,
+    memory_limit: Option<i64>,
+    oom_score_adj: Option<i64>,
+    cgroup_path: Option<String>,
+    cgroup_version: &'static str,
+    pid: Option<i64>,
+    security: Option<SecurityInfo>,
 }
 
-struct Collector {
+#[derive(Debug)]
+struct SecurityInfo {
+    privileged: bool,
+    readonly_rootfs: bool,
+    user: String,
+    devices: Vec<DeviceAccess>,
+    cap_add: Vec<String>,
+    cap_drop: Vec<String>,
+}
+
+#[derive(Debug)]
+struct DeviceAccess {
+    path: String,
+    permissions: String,
+}
+
+impl SecurityInfo {
+    fn from_inspect(inspect: &LibpodContainerInspectResponse) -> Self {
+        let host_config = inspect.host_config.as_ref();
+        let devices = host_config
+            .and_then(|c| c.devices.as_ref())
+            .into_iter()
+            .flatten()
+            .map(|d| DeviceAccess {
+                path: d.path_on_host.clone().unwrap_or_default(),
+                permissions: d.cgroup_permissions.clone().unwrap_or_default(),
+            })
+            .collect();
+        Self {
+            privileged: host_config.and_then(|c| c.privileged).unwrap_or(false),
+            readonly_rootfs: host_config.and_then(|c| c.readonly_rootfs).unwrap_or(false),
+            user: inspect
+                .config
+                .as_ref()
+                .and_then(|c| c.user.clone())
+                .unwrap_or_default(),
+            devices,
+            cap_add: capabilities(host_config.and_then(|c| c.cap_add.as_ref())),
+            cap_drop: capabilities(host_config.and_then(|c| c.cap_drop.as_ref())),
+        }
+    }
+
+    /// Without a user the container runs as the root user of its user namespace. Named users
+    /// other than `root` can't be resolved outside of the container and count as non-root.
+    fn runs_as_root(&self) -> bool {
+        let user = self.user.split(':').next().unwrap_or("");
+        user.is_empty() || user == "0" || user == "root"
+    }
+}
+
+/// The short ID is the first 12 hex digits, as in `podman images`.
+fn image_id(id: &str, full: bool) -> String {
+    if full {
+        id.to_string()
+    } else {
+        id_digits(id).chars().take(12).collect()
+    }
+}
+
+fn id_digits(id: &str) -> &str {
+    id.strip_prefix("sha256:").unwrap_or(id)
+}
+
+/// Podman reports the capabilities as `CAP_NET_ADMIN`, they are exposed without the prefix as
+/// in `--cap-add NET_ADMIN`.
+fn capabilities(caps: Option<&Vec<String>>) -> Vec<String> {
+    caps.into_iter()
+        .flatten()
+        .map(|cap| cap.strip_prefix("CAP_").unwrap_or(cap).to_string())
+        .collect()
+}
+
+/// Containers that aren't attached to a Podman network (host networking, slirp4netns,
+/// `container:<id>`, ...) report their network mode instead.
+fn container_networks(networks: Option<Vec<String>>, network_mode: &str) -> Vec<String> {
+    let networks: Vec<String> = networks
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|n| !n.is_empty())
+        .collect();
+    if !networks.is_empty() {
+        return networks;
+    }
+    if network_mode.is_empty() {
+        return Vec::new();
+    }
+    vec![network_mode.to_string()]
+}
+
+#[derive(Debug)]
+struct VolumeMount {
+    name: String,
+    destination: String,
+    read_only: bool,
+}
+
+/// Collects the named volume mounts, bind mounts and tmpfs are skipped.
+fn volume_mounts(inspect: &LibpodContainerInspectResponse) -> Vec<VolumeMount> {
+    inspect
+        .mounts
+        .iter()
+        .flatten()
+        .filter(|m| m._type.as_deref() == Some("volume"))
+        .map(|m| VolumeMount {
+            name: m.name.clone().unwrap_or_default(),
+            destination: m.destination.clone().unwrap_or_default(),
+            read_only: !m.RW.unwrap_or(true),
+        })
+        .collect()
+}
+
+/// The container labels under `prefix` as label pairs, named by the rest of the key. Names that
+/// are empty or reserved (`__` prefix) after stripping are skipped.
+fn dynamic_labels(labels: &HashMap<String, String>, prefix: &str) -> Vec<(String, String)> {
+    let mut pairs: Vec<(String, String)> = labels
+        .iter()
+        .filter_map(|(key, value)| {
+            let name = sanitize_label_name(key.strip_prefix(prefix)?);
+            if name.is_empty() || name.starts_with("__") {
+                return None;
+            }
+            Some((name, value.clone()))
+        })
+        .collect();
+    pairs.sort();
+    pairs
+}
+
+/// Only the allowlisted variables are kept, the environment often carries credentials.
+fn container_env(
+    inspect: &LibpodContainerInspectResponse,
+    vars: &[String],
+) -> HashMap<String, String> {
+    inspect
+        .config
+        .as_ref()
+        .and_then(|c| c.env.as_ref())
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.split_once('='))
+        .filter(|(key, _)| vars.iter().any(|var| var == key))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+#[derive(Debug)]
+struct RestartPolicy {
+    policy: String,
+    max_retry_count: i64,
+}
+
+/// An empty policy is the default, which doesn't restart the container.
+fn restart_policy(inspect: &LibpodContainerInspectResponse) -> RestartPolicy {
+    let policy = inspect
+        .host_config
+        .as_ref()
+        .and_then(|c| c.restart_policy.as_ref());
+    RestartPolicy {
+        policy: policy
+            .and_then(|p| p.name.clone())
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "no".to_string()),
+        max_retry_count: policy.and_then(|p| p.maximum_retry_count).unwrap_or(0),
+    }
+}
+
+#[derive(Debug)]
+struct Ulimit {
+    kind: String,
+    soft: i64,
+    hard: i64,
+}
+
+/// Podman reports the ulimits as `RLIMIT_NOFILE`, they are exposed as in `--ulimit nofile=...`.
+fn ulimits(inspect: &LibpodContainerInspectResponse) -> Vec<Ulimit> {
+    inspect
+        .host_config
+        .as_ref()
+        .and_then(|c| c.ulimits.as_ref())
+        .into_iter()
+        .flatten()
+        .filter_map(|ulimit| {
+            let name = ulimit.name.as_deref()?;
+            Some(Ulimit {
+                kind: name.strip_prefix("RLIMIT_").unwrap_or(name).to_lowercase(),
+                soft: ulimit.soft?,
+                hard: ulimit.hard?,
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+struct SecretRef {
+    name: String,
+    id: String,
+}
+
+fn container_secrets(inspect: &LibpodContainerInspectResponse) -> Vec<SecretRef> {
+    inspect
+        .config
+        .as_ref()
+        .and_then(|c| c.secrets.as_ref())
+        .into_iter()
+        .flatten()
+        .map(|s| SecretRef {
+            name: s.name.clone().unwrap_or_default(),
+            id: s.ID.clone().unwrap_or_default(),
+        })
+        .collect()
+}
+
+/// Prefers a name that isn't a pod infra container name (`<pod id>-infra`), skipping the
+/// leading slash some APIs add. Falls back to the first name.
+fn container_name(names: &[String]) -> Option<String> {
+    let names: Vec<&str> = names
+        .iter()
+        .map(|n| n.trim_start_matches('/'))
+        .filter(|n| !n.is_empty())
+        .collect();
+    names
+        .iter()
+        .find(|n| !n.ends_with("-infra"))
+        .or_else(|| names.first())
+        .map(|n| n.to_string())
+}
+
+/// `--name-exclude` takes precedence over `--name-include`.
+fn name_allowed(name: &str, include: Option<&Regex>, exclude: Option<&Regex>) -> bool {
+    if exclude.is_some_and(|re| re.is_match(name)) {
+        return false;
+    }
+    include.is_none_or(|re| re.is_match(name))
+}
+
+/// Maps the list `State` to the `podman_container_state` values.
+fn container_state(state: Option<&str>) -> isize {
+    match state {
+        Some("exited") => 0,
+        Some("stopped") => 0,
+        Some("running") => 1,
+        Some("created") => 2,
+        Some(_) | None => -1,
+    }
+}
+
+/// The pod of a listed container, containers outside of a pod have an empty `PodName`.
+fn pod_name(container: &ListContainer) -> Option<String> {
+    container.pod_name.clone().filter(|v| !v.is_empty())
+}
+
+/// Counts the CPUs in a cpuset list like `0-3,5`. Returns `None` for an empty or
+/// malformed list.
+fn parse_cpuset(cpuset: &str) -> Option<usize> {
+    let mut count = 0;
+    for range in cpuset.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+        count += match range.split_once('-') {
+            Some((start, end)) => {
+                let start: usize = start.parse().ok()?;
+                let end: usize = end.parse().ok()?;
+                end.checked_sub(start)? + 1
+            }
+            None => {
+                range.parse::<usize>().ok()?;
+                1
+            }
+        };
+    }
+    if count > 0 {
+        Some(count)
+    } else {
+        None
+    }
+}
+
+/// Containers without a cpuset restriction can use all CPUs of the Podman host.
+fn cpu_count(inspect: &LibpodContainerInspectResponse, host_cpus: Option<usize>) -> Option<usize> {
+    inspect
+        .host_config
+        .as_ref()
+        .and_then(|config| config.cpuset_cpus.as_deref())
+        .and_then(parse_cpuset)
+        .or(host_cpus)
+}
+
+/// Podman doesn't always report `up_time`, for running containers it is then computed from
+/// the start time. Like `up_time` the result is in nanoseconds.
+fn uptime_nanos(up_time: Option<i64>, cont: &ContInfo, now: i64) -> i64 {
+    match (up_time, cont.started_at) {
+        (Some(up_time), _) => up_time,
+        (None, Some(started_at)) if cont.state == 1 => (now - started_at).max(0) * 1_000_000_000,
+        (None, _) => 0,
+    }
+}
+
+struct CpuThrottling {
+    periods: f64,
+    seconds: f64,
+}
+
+/// Reads the first existing file of a container's cgroup out of `(hierarchy, file)` candidates,
+/// the unified hierarchy of cgroup v2 and the controller hierarchy of cgroup v1. This only works
+/// when the exporter sees the cgroup filesystem of the Podman host.
+fn read_cgroup_file(cgroup_path: &str, candidates: &[(&str, &str)]) -> Option<String> {
+    let cgroup_path = cgroup_path.trim_start_matches('/');
+    candidates.iter().find_map(|(root, file)| {
+        fs::read_to_string(Path::new(root).join(cgroup_path).join(file)).ok()
+    })
+}
+
+/// Checks the hierarchy that has the container's cgroup, which differs between containers on
+/// hybrid hosts. Containers without a cgroup (e.g. stopped ones) get the version of the host.
+fn cgroup_version(cgroup_path: Option<&str>) -> &'static str {
+    if let Some(cgroup_path) = cgroup_path.map(|p| p.trim_start_matches('/')) {
+        let unified = Path::new("/sys/fs/cgroup").join(cgroup_path);
+        if unified.join("cgroup.controllers").exists() {
+            return "v2";
+        }
+        let v1_roots = ["/sys/fs/cgroup/memory", "/sys/fs/cgroup/cpu,cpuacct"];
+        if v1_roots
+            .iter()
+            .any(|root| Path::new(root).join(cgroup_path).exists())
+        {
+            return "v1";
+        }
+    }
+    *HOST_CGROUP_VERSION
+}
+
+/// `v2` when the unified hierarchy is mounted on `/sys/fs/cgroup`, `v1` when there are cgroup
+/// v1 controller mounts (also on hybrid hosts).
+fn host_cgroup_version(mounts: &str) -> &'static str {
+    let mut version = "";
+    for line in mounts.lines() {
+        let mut fields = line.split_whitespace().skip(1);
+        match (fields.next(), fields.next()) {
+            (Some("/sys/fs/cgroup"), Some("cgroup2")) => return "v2",
+            (Some(_), Some("cgroup")) => version = "v1",
+            _ => (),
+        }
+    }
+    version
+}
+
+/// Reads the CPU throttling of a container from the `cpu.stat` of its cgroup.
+fn cpu_throttling(cgroup_path: &str) -> Option<CpuThrottling> {
+    let candidates = [
+        ("/sys/fs/cgroup", "cpu.stat"),
+        ("/sys/fs/cgroup/cpu,cpuacct", "cpu.stat"),
+    ];
+    read_cgroup_file(cgroup_path, &candidates).and_then(|cpu_stat| parse_cpu_stat(&cpu_stat))
+}
+
+/// cgroup v2 reports `throttled_usec`, cgroup v1 `throttled_time` in nanoseconds.
+fn parse_cpu_stat(cpu_stat: &str) -> Option<CpuThrottling> {
+    let mut periods = None;
+    let mut seconds = None;
+    for line in cpu_stat.lines() {
+        let (key, value) = match line.split_once(' ') {
+            Some((key, value)) => (key, value.trim().parse::<f64>().ok()),
+            None => continue,
+        };
+        match key {
+            "nr_throttled" => periods = value,
+            "throttled_usec" => seconds = value.map(|v| v / 1e6),
+            "throttled_time" => seconds = value.map(|v| v / 1e9),
+            _ => (),
+        }
+    }
+    Some(CpuThrottling {
+        periods: periods?,
+        seconds: seconds?,
+    })
+}
+
+struct InterfacePackets {
+    interface: String,
+    rx: f64,
+    tx: f64,
+}
+
+/// Reads the packet counts of a container's interfaces from `/proc/<pid>/net/dev`, which shows
+/// the network namespace of the container's main process. This needs the exporter to see the
+/// `/proc` of the Podman host.
+fn interface_packets(pid: i64) -> Vec<InterfacePackets> {
+    fs::read_to_string(format!("/proc/{}/net/dev", pid))
+        .map(|net_dev| parse_net_dev(&net_dev))
+        .unwrap_or_default()
+}
+
+/// The first two lines are headers, then `<iface>: <8 receive fields> <8 transmit fields>`
+/// with the packets second in each group. The loopback interface is skipped.
+fn parse_net_dev(net_dev: &str) -> Vec<InterfacePackets> {
+    net_dev
+        .lines()
+        .skip(2)
+        .filter_map(|line| {
+            let (interface, counters) = line.split_once(':')?;
+            let interface = interface.trim();
+            if interface == "lo" {
+                return None;
+            }
+            let counters: Vec<f64> = counters
+                .split_whitespace()
+                .map(|v| v.parse().ok())
+                .collect::<Option<_>>()?;
+            Some(InterfacePackets {
+                interface: interface.to_string(),
+                rx: *counters.get(1)?,
+                tx: *counters.get(9)?,
+            })
+        })
+        .collect()
+}
+
+/// Counts the open file descriptors of a container's main process, which needs the exporter to
+/// see the `/proc` of the Podman host and to run as the container's user or root.
+fn open_fds(pid: i64) -> Option<usize> {
+    fs::read_dir(format!("/proc/{}/fd", pid))
+        .ok()
+        .map(|fds| fds.count())
+}
+
+struct BlkioOps {
+    read: f64,
+    write: f64,
+}
+
+/// Reads the block I/O operations of a container summed over the devices, from `io.stat` on
+/// cgroup v2 and `blkio.throttle.io_serviced_recursive` on cgroup v1.
+fn blkio_ops(cgroup_path: &str) -> Option<BlkioOps> {
+    let candidates = [
+        ("/sys/fs/cgroup", "io.stat"),
+        (
+            "/sys/fs/cgroup/blkio",
+            "blkio.throttle.io_serviced_recursive",
+        ),
+    ];
+    read_cgroup_file(cgroup_path, &candidates).map(|stat| parse_blkio_ops(&stat))
+}
+
+/// cgroup v2 lines are `<dev> rbytes=.. wbytes=.. rios=.. wios=..`, cgroup v1 lines
+/// `<dev> Read <ops>` and `<dev> Write <ops>` followed by a `Total` line.
+fn parse_blkio_ops(stat: &str) -> BlkioOps {
+    let mut ops = BlkioOps {
+        read: 0.0,
+        write: 0.0,
+    };
+    for line in stat.lines() {
+        let fields: Vec<&str> = line.split_whitespace().skip(1).collect();
+        match fields.as_slice() {
+            ["Read", value] => ops.read += value.parse().unwrap_or(0.0),
+            ["Write", value] => ops.write += value.parse().unwrap_or(0.0),
+            _ => {
+                for (key, value) in fields.iter().filter_map(|f| f.split_once('=')) {
+                    match key {
+                        "rios" => ops.read += value.parse().unwrap_or(0.0),
+                        "wios" => ops.write += value.parse().unwrap_or(0.0),
+                        _ => (),
+                    }
+                }
+            }
+        }
+    }
+    ops
+}
+
+/// cgroup v1 reports a missing limit as the largest page-aligned i64.
+const MEM_LIMIT_SENTINEL: i64 = 1 << 62;
+
+/// A container has no memory limit when its configured limit is 0. Without an inspect
+/// response, a stats limit of at least the host memory or the cgroup v1 sentinel counts too,
+/// since Podman reports the host memory for unlimited containers.
+fn memory_unlimited(mem_limit: i64, configured: Option<i64>, host_memory: Option<i64>) -> bool {
+    match configured {
+        Some(configured) => configured == 0,
+        None => {
+            mem_limit >= MEM_LIMIT_SENTINEL || host_memory.is_some_and(|host| mem_limit >= host)
+        }
+    }
+}
+
+/// The stats of a pod, summed over its containers. `podman_api` types the response of the pods
+/// stats endpoint as a `top` response, so they can't be taken from Podman directly.
+#[derive(Debug, Default)]
+struct PodStats {
+    cpu: f64,
+    mem_usage: f64,
+    net_input: f64,
+    net_output: f64,
+}
+
+impl PodStats {
+    /// The containers of a pod share the network namespace of the infra container and report
+    /// the same network stats, so they are taken once instead of summed.
+    fn add(&mut self, stat: &LibpodContainerStats) {
+        let (net_input, net_output) = network_totals(stat);
+        self.cpu += stat.CPU.unwrap_or(0.0) as f64;
+        self.mem_usage += stat.mem_usage.unwrap_or(0) as f64;
+        self.net_input = self.net_input.max(net_input as f64);
+        self.net_output = self.net_output.max(net_output as f64);
+    }
+}
+
+/// Returns the network input and output bytes summed over all interfaces of a container.
+/// The libpod stats API already reports them summed over the interfaces.
+fn network_totals(stat: &LibpodContainerStats) -> (i64, i64) {
+    (stat.net_input.unwrap_or(0), stat.net_output.unwrap_or(0))
+}
+
+/// Splits the CPU time into user and kernel mode. The libpod stats report the total and the
+/// kernel mode time (`cpu_system_nano`, the cgroup's `usage_in_kernelmode`).
+fn cpu_user_kernel(stat: &LibpodContainerStats) -> (i64, i64) {
+    let kernel = stat.cpu_system_nano.unwrap_or(0);
+    let user = stat.cpu_nano.unwrap_or(0).saturating_sub(kernel).max(0);
+    (user, kernel)
+}
+
+/// Containers outside of a pod are not counted.
+fn count_containers_per_pod(containers: &HashMap<String, ContInfo>) -> HashMap<String, usize> {
+    let mut pods: HashMap<String, usize> = HashMap::new();
+    for cont in containers.values() {
+        if let Some(pod) = cont.pod.clone() {
+            let container_cnt = pods.entry(pod).or_insert(0);
+            *container_cnt += 1;
+        }
+    }
+    pods
+}
+
+/// Counts the running and the exited or stopped containers of every pod.
+fn count_pod_containers_by_state(
+    containers: &HashMap<String, ContInfo>,
+) -> HashMap<String, (usize, usize)> {
+    let mut pods: HashMap<String, (usize, usize)> = HashMap::new();
+    for cont in containers.values() {
+        if let Some(pod) = cont.pod.clone() {
+            let (running, stopped) = pods.entry(pod).or_default();
+            match cont.state_name.as_str() {
+                "running" => *running += 1,
+                "exited" | "stopped" => *stopped += 1,
+                _ => (),
+            }
+        }
+    }
+    pods
+}
+
+/// Counts the names shared by more than one container, which `--no-pod-label` would merge into
+/// one series.
+fn count_name_collisions(containers: &HashMap<String, ContInfo>) -> usize {
+    let mut names: HashMap<&str, usize> = HashMap::new();
+    for cont in containers.values() {
+        *names.entry(cont.name.as_str()).or_insert(0) += 1;
+    }
+    names.values().filter(|cnt| **cnt > 1).count()
+}
+
+/// Counts the pods per lowercased Podman pod status. The usual states are always present so
+/// that alerts on them don't go stale.
+fn count_pods_by_state(statuses: &[String]) -> HashMap<String, usize> {
+    let mut states: HashMap<String, usize> = ["running", "stopped", "created", "degraded"]
+        .iter()
+        .map(|s| (s.to_string(), 0))
+        .collect();
+    for status in statuses {
+        *states.entry(status.to_lowercase()).or_insert(0) += 1;
+    }
+    states
+}
+
+struct SystemDf {
+    images: i64,
+    containers: i64,
+    volumes: i64,
+}
+
+#[derive(Debug)]
+struct CollectorConfig {
+    min_api_version: ApiVersion,
+    system_df_interval: Duration,
+    name_from: ContainerNameFrom,
+    expose_labels: Vec<String>,
+    expose_env: Vec<String>,
+    dynamic_label_prefix: Option<String>,
+    name_include: Option<Regex>,
+    name_exclude: Option<Regex>,
+    exclude_infra: bool,
+    ignore_pods: Vec<String>,
+    running_only: bool,
+    full_image_id: bool,
+    collect_security: bool,
+    groups: Vec<MetricGroup>,
+    collect_sizes: bool,
+    collect_interface_packets: bool,
+    collect_fds: bool,
+    collect_pod_stats: bool,
+    collect_started_at: bool,
+    count_starts: bool,
+    start_count_from_inspect: bool,
+    cache_inspect: bool,
+    inspect_concurrency: usize,
+    reconnect_after: usize,
+    ssh: SshOptions,
+    tls: TlsOptions,
+}
+
+/// Settings of the HTTP server and of the gathered metrics.
+#[derive(Debug)]
+struct ServerConfig {
+    metrics_path: String,
+    /// The events keep the metrics up to date, so requests don't collect.
+    event_driven: bool,
+    access_log: bool,
+    max_scrape_timeout: Option<f64>,
+    exporter_metrics: bool,
+    metric_naming: MetricNaming,
+    external_labels: Vec<ExternalLabel>,
+}
+
+impl ServerConfig {
+    fn from_args(args: &AppArgs) -> Self {
+        Self {
+            metrics_path: args.metrics_path.clone(),
+            event_driven: args.event_driven,
+            access_log: args.access_log,
+            max_scrape_timeout: args.max_scrape_timeout,
+            exporter_metrics: !args.disable_exporter_metrics,
+            metric_naming: args.metric_naming.clone(),
+            external_labels: args.external_labels.clone(),
+        }
+    }
+}
+
+impl MetricsConfig {
+    fn from_args(args: &AppArgs) -> Self {
+        Self {
+            expose_labels: args.expose_labels.clone(),
+            expose_env: args.expose_env.clone(),
+            max_label_combinations: args.max_label_combinations,
+            no_pod_label: args.no_pod_label,
+            process_metrics: !args.disable_process_metrics,
+        }
+    }
+}
+
+/// All groups but the systemd units when `--collector.enable-only` isn't given, without the
+/// container stats when they are disabled and the systemd units without `--systemd`.
+fn enabled_groups(args: &AppArgs) -> Vec<MetricGroup> {
+    let groups = if args.enable_only.is_empty() {
+        MetricGroup::value_variants()
+            .iter()
+            .copied()
+            .filter(|g| *g != MetricGroup::SystemdUnits)
+            .collect()
+    } else {
+        args.enable_only.clone()
+    };
+    groups
+        .into_iter()
+        .filter(|g| !(args.disable_container_stats && *g == MetricGroup::ContainerStats))
+        .filter(|g| args.systemd || *g != MetricGroup::SystemdUnits)
+        .collect()
+}
+
+impl CollectorConfig {
+    fn collects(&self, group: MetricGroup) -> bool {
+        self.groups.contains(&group)
+    }
+
+    /// Whether an enabled group or flag reads values that only the container inspect has, the
+    /// state, pod and image groups get along with the container list.
+    fn needs_inspect(&self) -> bool {
+        self.collects(MetricGroup::ContainerStats)
+            || self.collects(MetricGroup::ContainerInfo)
+            || self.collects(MetricGroup::NetworkInfo)
+            || self.collects(MetricGroup::VolumeInfo)
+            || self.collects(MetricGroup::SecretInfo)
+            || self.collect_started_at
+            || matches!(self.name_from, ContainerNameFrom::Hostname)
+            || (self.count_starts && self.start_count_from_inspect)
+    }
+
+    fn from_args(args: &AppArgs) -> Self {
+        Self {
+            min_api_version: args.min_api_version,
+            system_df_interval: Duration::from_secs(args.system_df_interval),
+            name_from: args.container_name_from.clone(),
+            expose_labels: args.expose_labels.clone(),
+            expose_env: args.expose_env.clone(),
+            dynamic_label_prefix: args
+                .dynamic_label_prefix
+                .clone()
+                .filter(|_| args.dynamic_labels),
+            name_include: args.name_include.clone(),
+            name_exclude: args.name_exclude.clone(),
+            exclude_infra: args.exclude_infra,
+            ignore_pods: args.ignore_pods.clone(),
+            running_only: args.running_only,
+            full_image_id: args.full_image_id,
+            collect_security: args.collect_security,
+            groups: enabled_groups(args),
+            collect_sizes: args.collect_sizes,
+            collect_interface_packets: args.collect_interface_packets,
+            collect_fds: args.collect_fds,
+            collect_pod_stats: args.collect_pod_stats,
+            collect_started_at: args.collect_started_at,
+            count_starts: args.event_driven,
+            start_count_from_inspect: args.start_count_from_inspect,
+            cache_inspect: args.collect_exited_only_once,
+            inspect_concurrency: args.inspect_concurrency,
+            reconnect_after: args.reconnect_after,
+            ssh: SshOptions {
+                identity: args.podman_ssh_identity.clone(),
+                passphrase_file: args.podman_ssh_passphrase_file.clone(),
+            },
+            tls: TlsOptions {
+                ca: args.podman_tcp_tls_ca.clone(),
+                cert: args.podman_tcp_tls_cert.clone(),
+                key: args.podman_tcp_tls_key.clone(),
+            },
+        }
+    }
+}
+
+/// Stopped containers are only listed without `--running-only`.
+fn container_list_opts(config: &CollectorConfig) -> ContainerListOpts {
+    ContainerListOpts::builder()
+        .all(!config.running_only)
+        .size(config.collect_sizes)
+        .build()
+}
+
+/// A Podman client with the ssh tunnel it talks through for `ssh://` URIs.
+struct Connection {
     podman: Podman,
+    /// Keeps the forwarded socket of an `ssh://` URI open.
+    _ssh_tunnel: Option<SshTunnel>,
+}
+
+/// The schemes of `--podman` URIs, for the error messages.
+const URI_SCHEMES: &str = "unix://, tcp://, http:// or ssh://";
+
+impl Connection {
+    /// Blocks for up to the ssh tunnel timeout with an `ssh://` URI.
+    fn open(uri: &str, ssh: &SshOptions, tls: &TlsOptions) -> Result<Self> {
+        let (scheme, authority) = uri
+            .split_once("://")
+            .ok_or_else(|| anyhow!("Podman URI {} has no scheme, expected {}", uri, URI_SCHEMES))?;
+        if tls.is_enabled() && scheme != "tcp" {
+            return Err(anyhow!(
+                "--podman-tcp-tls-* flags need a tcp:// Podman URI, got {}",
+                uri
+            ));
+        }
+        let mut ssh_tunnel = None;
+        let podman = match scheme {
+            "unix" | "tcp" | "http" if !tls.is_enabled() => {
+                Podman::new(uri).map_err(|e| anyhow!("Create Podman interface: {}", e))?
+            }
+            "tcp" => tls::connect(authority, tls)?,
+            "ssh" => {
+                let tunnel =
+                    SshTunnel::open(uri, ssh).map_err(|e| anyhow!("Open ssh tunnel: {}", e))?;
+                info!(uri = %tunnel.uri(), "Forwarding Podman socket over ssh");
+                let podman = Podman::new(tunnel.uri())
+                    .map_err(|e| anyhow!("Create Podman interface: {}", e))?;
+                ssh_tunnel = Some(tunnel);
+                podman
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Unsupported Podman URI scheme {}://, expected {}",
+                    scheme,
+                    URI_SCHEMES
+                ))
+            }
+        };
+        Ok(Self {
+            podman,
+            _ssh_tunnel: ssh_tunnel,
+        })
+    }
+}
+
+/// The Podman client is built once and shared by all scrapes, it is only rebuilt after
+/// `reconnect_after` failed collections in a row. Note that `podman_api` builds its unix socket
+/// client with `pool_max_idle_per_host(0)` and doesn't expose the underlying `hyper::Client`, so
+/// every API request still opens a fresh socket connection.
+struct Collector {
+    uri: String,
+    connection: RwLock<Arc<Connection>>,
+    consecutive_failures: Mutex<usize>,
+    metrics: Arc<Metrics>,
+    config: CollectorConfig,
+    api_version: RwLock<Option<ApiVersion>>,
+    system_df_updated: Mutex<Option<Instant>>,
+    /// Inspect responses by container ID, with the container state they were taken in.
+    inspect_cache: Mutex<HashMap<String, (Option<String>, LibpodContainerInspectResponse)>>,
+    host: RwLock<Option<HostInfo>>,
+    start_counts: Mutex<HashMap<String, StartCount>>,
+    systemd: AsyncMutex<Option<SystemdClient>>,
+    /// Image digests by image ID, `None` for images without a digest (e.g. built locally).
+    image_digests: Mutex<HashMap<String, Option<String>>>,
+    /// Held by a collection from the first reset to the last value, and while gathering.
+    collection: AsyncMutex<()>,
+}
+
+/// The host values of `podman info`.
+#[derive(Debug, Clone, Copy, Default)]
+struct HostInfo {
+    mem_total: Option<i64>,
+    cpus: Option<usize>,
+}
+
+/// The starts of a container seen in the events, on top of `base` starts from before the
+/// container was first collected.
+#[derive(Debug, Default)]
+struct StartCount {
+    base: Option<u64>,
+    events: u64,
 }
 
 impl Collector {
-    fn new<U: AsRef<str>>(uri: U) -> Result<Self> {
-        let podman = Podman::new(uri).map_err(|e| anyhow!("Create Podman interface: {}", e))?;
-        Ok(Self { podman: podman })
+    fn new<U: AsRef<str>>(uri: U, config: CollectorConfig, metrics: Arc<Metrics>) -> Result<Self> {
+        let uri = uri.as_ref();
+        let local = uri.starts_with("unix://");
+        // The packets and descriptors are read from the local /proc, which only has the
+        // containers of a local Podman.
+        if config.collect_interface_packets && !local {
+            return Err(anyhow!(
+                "--collect-interface-packets needs a unix:// Podman URI, got {}",
+                uri
+            ));
+        }
+        if config.collect_fds && !local {
+            return Err(anyhow!(
+                "--collect-fds needs a unix:// Podman URI, got {}",
+                uri
+            ));
+        }
+        let connection = Connection::open(uri, &config.ssh, &config.tls)?;
+        Ok(Self {
+            uri: uri.to_string(),
+            connection: RwLock::new(Arc::new(connection)),
+            consecutive_failures: Mutex::new(0),
+            metrics,
+            config,
+            api_version: RwLock::new(None),
+            system_df_updated: Mutex::new(None),
+            inspect_cache: Mutex::new(HashMap::new()),
+            host: RwLock::new(None),
+            start_counts: Mutex::new(HashMap::new()),
+            systemd: AsyncMutex::new(None),
+            image_digests: Mutex::new(HashMap::new()),
+            collection: AsyncMutex::new(()),
+        })
+    }
+
+    fn connection(&self) -> Arc<Connection> {
+        self.connection.read().unwrap().clone()
+    }
+
+    fn podman(&self) -> Podman {
+        self.connection().podman.clone()
+    }
+
+    /// Rebuilds the client, e.g. after Podman was restarted and recreated its socket.
+    async fn reconnect(&self) -> Result<()> {
+        let (uri, ssh, tls) = (
+            self.uri.clone(),
+            self.config.ssh.clone(),
+            self.config.tls.clone(),
+        );
+        let connection = tokio::task::spawn_blocking(move || Connection::open(&uri, &ssh, &tls))
+            .await
+            .map_err(|e| anyhow!("Reconnect task: {}", e))??;
+        *self.connection.write().unwrap() = Arc::new(connection);
+        self.negotiate_api_version().await?;
+        Ok(())
+    }
+
+    /// Counts the failed collections in a row and reconnects after `reconnect_after` of them.
+    async fn track_failure(&self, failed: bool) {
+        let failures = {
+            let mut failures = self.consecutive_failures.lock().unwrap();
+            *failures = if failed { *failures + 1 } else { 0 };
+            *failures
+        };
+        let reconnect_after = self.config.reconnect_after;
+        if reconnect_after == 0 || failures < reconnect_after {
+            return;
+        }
+        info!(failures, uri = %self.uri, "Reconnecting to Podman");
+        *self.consecutive_failures.lock().unwrap() = 0;
+        if let Err(err) = self.reconnect().await {
+            warn!(error = %err, "Podman reconnect failed");
+        }
+    }
+
+    async fn negotiate_api_version(&self) -> Result<ApiVersion> {
+        let ping = self
+            .podman()
+            .ping()
+            .await
+            .map_err(|e| anyhow!("Ping request: {}", e))?;
+        let version = ApiVersion::from_str(&ping.libpod_api_version)
+            .map_err(|e| anyhow!("Parse API version {}: {}", ping.libpod_api_version, e))?;
+        *self.api_version.write().unwrap() = Some(version);
+        Ok(version)
     }
 
-    async fn containers(&self) -> Result<HashMap<String, ContInfo>> {
+    /// PIDs and network I/O are only reported by newer Podman versions. When the version is
+    /// not known yet they are collected anyway.
+    fn extended_stats_supported(&self) -> bool {
+        match *self.api_version.read().unwrap() {
+            Some(version) => version >= self.config.min_api_version,
+            None => true,
+        }
+    }
+
+    /// The host doesn't change, so `podman info` is only asked until it answered.
+    async fn host_info(&self) -> HostInfo {
+        if let Some(host) = *self.host.read().unwrap() {
+            return host;
+        }
+        match self.podman().info().await {
+            Ok(info) => {
+                let host = info.host.map_or_else(HostInfo::default, |h| HostInfo {
+                    mem_total: h.mem_total,
+                    cpus: h
+                        .cpus
+                        .and_then(|n| usize::try_from(n).ok())
+                        .filter(|n| *n > 0),
+                });
+                *self.host.write().unwrap() = Some(host);
+                host
+            }
+            Err(err) => {
+                warn!(error = %err, "Info request failed");
+                HostInfo::default()
+            }
+        }
+    }
+
+    /// Returns the disk usage of images, containers and volumes, as `podman system df`.
+    async fn system_df(&self) -> Result<SystemDf> {
+        let resp = self
+            .podman()
+            .data_usage()
+            .await
+            .map_err(|e| anyhow!("System df request: {}", e))?;
+        let images = resp
+            .images
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|i| i.size)
+            .sum();
+        let containers = resp
+            .containers
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|c| c.rw_size)
+            .sum();
+        let volumes = resp
+            .volumes
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|v| v.size)
+            .sum();
+        Ok(SystemDf {
+            images,
+            containers,
+            volumes,
+        })
+    }
+
+    async fn update_pods(&self) -> Result<()> {
+        let pods = self
+            .podman()
+            .pods()
+            .list(&Default::default())
+            .await
+            .map_err(|e| anyhow!("Pods request: {}", e))?;
+        let statuses: Vec<String> = pods
+            .into_iter()
+            .filter(|p| {
+                p.name
+                    .as_ref()
+                    .is_none_or(|name| !self.config.ignore_pods.contains(name))
+            })
+            .filter_map(|p| p.status)
+            .collect();
+        self.metrics.pod_by_state.reset();
+        for (state, cnt) in count_pods_by_state(&statuses) {
+            self.metrics
+                .pod_by_state
+                .with_label_values(&[&state])
+                .set(cnt as f64);
+        }
+        Ok(())
+    }
+
+    async fn update_secrets(&self) -> Result<()> {
+        let secrets = self
+            .podman()
+            .secrets()
+            .list()
+            .await
+            .map_err(|e| anyhow!("Secrets request: {}", e))?;
+        self.metrics.secret_total.set(secrets.len() as f64);
+        Ok(())
+    }
+
+    /// Image digests by image ID, without the `sha256:` prefix of the ID. The ID is a content
+    /// hash, so the images are only listed again when a container uses an image that isn't
+    /// cached yet.
+    async fn image_digests(
+        &self,
+        containers: &HashMap<String, ContInfo>,
+    ) -> Result<HashMap<String, Option<String>>> {
+        let missing = {
+            let cached = self.image_digests.lock().unwrap();
+            containers
+                .values()
+                .filter(|cont| !cont.image_id.is_empty())
+                .any(|cont| !cached.contains_key(id_digits(&cont.image_id)))
+        };
+        if missing {
+            let images = self
+                .podman()
+                .images()
+                .list(&Default::default())
+                .await
+                .map_err(|e| anyhow!("Images request: {}", e))?;
+            let digests = images
+                .into_iter()
+                .filter_map(|image| Some((id_digits(&image.id?).to_string(), image.digest)))
+                .collect();
+            *self.image_digests.lock().unwrap() = digests;
+        }
+        Ok(self.image_digests.lock().unwrap().clone())
+    }
+
+    /// Disk usage changes slowly and is expensive to compute, so it is only refreshed once
+    /// per `system_df_interval`.
+    async fn update_system_df(&self) -> Result<()> {
+        let due = match *self.system_df_updated.lock().unwrap() {
+            Some(updated) => updated.elapsed() >= self.config.system_df_interval,
+            None => true,
+        };
+        if !due {
+            return Ok(());
+        }
+        let df = self.system_df().await?;
+        self.metrics.system_images_size.set(df.images as f64);
+        self.metrics
+            .system_containers_size
+            .set(df.containers as f64);
+        self.metrics.system_volumes_size.set(df.volumes as f64);
+        self.metrics
+            .system_total_size
+            .set((df.images + df.containers + df.volumes) as f64);
+        *self.system_df_updated.lock().unwrap() = Some(Instant::now());
+        Ok(())
+    }
+
+    async fn inspect(&self, id: &str) -> Result<LibpodContainerInspectResponse> {
+        self.podman()
+            .containers()
+            .get(id)
+            .inspect()
+            .await
+            .map_err(|e| anyhow!("Inspect request: {}", e))
+    }
+
+    /// With `--collect-exited-only-once` a container is only inspected again after its state
+    /// changed, so the inspect cost follows state transitions rather than the scrape frequency.
+    async fn inspect_cached(
+        &self,
+        id: &str,
+        state: Option<&String>,
+    ) -> Result<LibpodContainerInspectResponse> {
+        if !self.config.cache_inspect {
+            return self.inspect(id).await;
+        }
+        if let Some((cached_state, inspect)) = self.inspect_cache.lock().unwrap().get(id) {
+            if cached_state.as_ref() == state {
+                return Ok(inspect.clone());
+            }
+        }
+        let inspect = self.inspect(id).await?;
+        self.inspect_cache
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), (state.cloned(), inspect.clone()));
+        Ok(inspect)
+    }
+
+    /// The collected containers by ID, and the IDs of the listed containers left out by the
+    /// filters.
+    async fn containers(&self) -> Result<(HashMap<String, ContInfo>, HashSet<String>)> {
         let containers_resp = self
-            .podman
+            .podman()
             .containers()
-            .list(&ContainerListOpts::builder().all(true).build())
+            .list(&container_list_opts(&self.config))
             .await
             .map_err(|e| anyhow!("Containers request: {}", e))?;
-        let mut result = HashMap::new();
+        let mut listed = Vec::new();
+        let mut filtered = HashSet::new();
         for container in containers_resp {
-            let id = match container.id {
+            let id = match container.id.clone() {
                 Some(id) => id,
                 None => continue,
             };
-            let pod = container.pod_name.filter(|v| v != "");
-            let name = container
-                .names
-                .map(|ns| ns.first().map(String::from))
-                .flatten();
+            let name = container.names.as_deref().and_then(container_name);
             let name = match name {
                 Some(n) => n,
-                None => continue,
-            };
-            let state = match container.state.as_ref().map(String::as_ref) {
-                Some("existed") => 0,
-                Some("stopped") => 0,
-                Some("running") => 1,
-                Some("created") => 2,
-                Some(_) | None => -1,
+                None => {
+                    filtered.insert(id);
+                    continue;
+                }
             };
-            let uptime = match container.started_at {
-                Some(t) => (Utc::now()).timestamp() - t,
-                None => 0,
+            // Filtered before the inspect, so excluded containers cost no API calls.
+            if !self.collects_container(&name, &container) {
+                filtered.insert(id);
+                continue;
+            }
+            listed.push((id, name, container));
+        }
+        let needs_inspect = self.config.needs_inspect();
+        let host_cpus = if needs_inspect && self.config.collects(MetricGroup::ContainerStats) {
+            self.host_info().await.cpus
+        } else {
+            None
+        };
+        // A failed inspect only leaves out the inspect based values of that container.
+        let inspected: Vec<_> = stream::iter(listed)
+            .map(|(id, name, container)| async move {
+                if !needs_inspect {
+                    return (id, name, container, None);
+                }
+                let inspect = match self.inspect_cached(&id, container.state.as_ref()).await {
+                    Ok(inspect) => Some(inspect),
+                    Err(err) => {
+                        warn!(container_id = %id, error = %err, "Container inspect failed");
+                        None
+                    }
+                };
+                (id, name, container, inspect)
+            })
+            .buffer_unordered(self.config.inspect_concurrency.max(1))
+            .collect()
+            .await;
+        let result: HashMap<String, ContInfo> = inspected
+            .into_iter()
+            .map(|(id, name, container, inspect)| {
+                let cont = self.cont_info(name, container, inspect, host_cpus);
+                (id, cont)
+            })
+            .collect();
+        // Forget the removed containers.
+        self.inspect_cache
+            .lock()
+            .unwrap()
+            .retain(|id, _| result.contains_key(id));
+        Ok((result, filtered))
+    }
+
+    /// Applies `--name-include`, `--name-exclude`, `--exclude-infra` and `--ignore-pod`.
+    fn collects_container(&self, name: &str, container: &ListContainer) -> bool {
+        let include = self.config.name_include.as_ref();
+        if !name_allowed(name, include, self.config.name_exclude.as_ref()) {
+            return false;
+        }
+        if self.config.exclude_infra && container.is_infra.unwrap_or(false) {
+            return false;
+        }
+        pod_name(container).is_none_or(|pod| !self.config.ignore_pods.contains(&pod))
+    }
+
+    fn cont_info(
+        &self,
+        name: String,
+        container: ListContainer,
+        inspect: Option<LibpodContainerInspectResponse>,
+        host_cpus: Option<usize>,
+    ) -> ContInfo {
+        let pod = pod_name(&container);
+        let is_infra = container
+            .is_infra
+            .or_else(|| inspect.as_ref().and_then(|i| i.is_infra))
+            .unwrap_or(false);
+        let state = container_state(container.state.as_deref());
+        let uptime = match container.started_at {
+            Some(t) => (Utc::now()).timestamp() - t,
+            None => 0,
+        };
+        let name = match &self.config.name_from {
+            ContainerNameFrom::Name => None,
+            ContainerNameFrom::Label(key) => {
+                container.labels.as_ref().and_then(|labels| labels.get(key))
+            }
+            ContainerNameFrom::Hostname => inspect
+                .as_ref()
+                .and_then(|i| i.config.as_ref())
+                .and_then(|c| c.hostname.as_ref()),
+        }
+        .filter(|v| !v.is_empty())
+        .cloned()
+        .unwrap_or(name);
+        let started_at = inspect
+            .as_ref()
+            .and_then(|i| i.state.as_ref())
+            .and_then(|state| state.started_at)
+            .map(|t| t.timestamp())
+            .or(container.started_at)
+            .filter(|t| *t > 0);
+        let cpu_count = inspect.as_ref().and_then(|i| cpu_count(i, host_cpus));
+        let memory_limit = inspect
+            .as_ref()
+            .and_then(|i| i.host_config.as_ref())
+            .and_then(|c| c.memory);
+        let volumes = inspect.as_ref().map(volume_mounts);
+        let env = inspect
+            .as_ref()
+            .map(|i| container_env(i, &self.config.expose_env))
+            .unwrap_or_default();
+        let secrets = inspect.as_ref().map(container_secrets);
+        let restart_policy = inspect.as_ref().map(restart_policy);
+        let ulimits = inspect.as_ref().map(ulimits).unwrap_or_default();
+        let cgroup_path = inspect
+            .as_ref()
+            .and_then(|i| i.state.as_ref())
+            .and_then(|state| state.cgroup_path.clone())
+            .filter(|path| !path.is_empty());
+        let pid = inspect
+            .as_ref()
+            .and_then(|i| i.state.as_ref())
+            .and_then(|state| state.pid)
+            .filter(|pid| *pid > 0);
+        let network_mode = inspect
+            .as_ref()
+            .and_then(|i| i.host_config.as_ref())
+            .and_then(|c| c.network_mode.clone())
+            .unwrap_or_default();
+        let networks = container_networks(container.networks, &network_mode);
+        let user_namespace = inspect
+            .as_ref()
+            .and_then(|i| i.host_config.as_ref())
+            .and_then(|c| c.userns_mode.clone())
+            .unwrap_or_default();
+        let security = inspect
+            .as_ref()
+            .filter(|_| self.config.collect_security)
+            .map(SecurityInfo::from_inspect);
+        ContInfo {
+            pod,
+            name,
+            image: container.image.unwrap_or_default(),
+            image_id: container.image_id.unwrap_or_default(),
+            is_infra,
+            labels: container.labels.unwrap_or_default(),
+            env,
+            mounts: container.mounts.map_or(0, |m| m.len()),
+            volumes,
+            secrets,
+            networks,
+            network_mode,
+            user_namespace,
+            rw_size: container.size.as_ref().and_then(|s| s.rw_size),
+            rootfs_size: container.size.as_ref().and_then(|s| s.root_fs_size),
+            restart_policy,
+            ulimits,
+            state,
+            state_name: container.state.clone().unwrap_or_default(),
+            uptime,
+            started_at,
+            restart_count: inspect.as_ref().and_then(|i| i.restart_count),
+            cpu_count,
+            memory_limit,
+            oom_score_adj: inspect
+                .as_ref()
+                .and_then(|i| i.host_config.as_ref())
+                .and_then(|c| c.oom_score_adj),
+            cgroup_version: cgroup_version(cgroup_path.as_deref()),
+            cgroup_path,
+            pid,
+            security,
+        }
+    }
+
+    /// Streams the container lifecycle events that change the set of collected containers.
+    fn events(podman: &Podman) -> impl Stream<Item = Result<Event>> + '_ {
+        let filters = vec![
+            ("type".to_string(), vec!["container".to_string()]),
+            (
+                "event".to_string(),
+                CONTAINER_EVENTS.iter().map(|e| e.to_string()).collect(),
+            ),
+        ];
+        podman
+            .events(&EventsOpts::builder().filters(filters).build())
+            .map(|event| event.map_err(|e| anyhow!("Events request: {}", e)))
+    }
+
+    /// Collects on every container event, and every `poll_interval` without one so that the
+    /// stats keep moving. While the events stream is unavailable the collection falls back to
+    /// polling alone.
+    async fn watch_events(&self, poll_interval: Duration) {
+        loop {
+            if let Err(err) = self.update_stat().await {
+                error!(error = %err, "Collection failed");
+            }
+            let podman = self.podman();
+            let mut events = Self::events(&podman);
+            let mut polls = time::interval_at(time::Instant::now() + poll_interval, poll_interval);
+            polls.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+            loop {
+                let event = tokio::select! {
+                    event = events.next() => event,
+                    _ = polls.tick() => {
+                        if let Err(err) = self.update_stat().await {
+                            error!(error = %err, "Collection failed");
+                        }
+                        continue;
+                    }
+                };
+                let event = match event {
+                    Some(event) => event,
+                    None => break,
+                };
+                match event {
+                    Ok(event) => {
+                        debug!(action = %event.action, container_id = %event.actor.id, "Container event");
+                        if event.action == "start" {
+                            let mut start_counts = self.start_counts.lock().unwrap();
+                            start_counts
+                                .entry(event.actor.id.clone())
+                                .or_default()
+                                .events += 1;
+                        }
+                        if let Err(err) = self.update_stat().await {
+                            error!(error = %err, "Collection failed");
+                        }
+                        polls.reset();
+                    }
+                    Err(err) => {
+                        warn!(error = %err, "Events stream failed");
+                        break;
+                    }
+                }
+            }
+            warn!(
+                poll_interval = ?poll_interval,
+                "Events stream disconnected, polling until it reconnects"
+            );
+            time::sleep(poll_interval).await;
+        }
+    }
+
+    /// Retries a failed collection with exponential backoff, Podman API errors are usually
+    /// transient (a restarting service, a reset connection). When all attempts fail the metrics
+    /// keep the values of the last successful collection. The collection timestamp is only
+    /// updated when the whole collection succeeded.
+    async fn update_stat(&self) -> Result<()> {
+        let mut result = self.collect_exclusive().await;
+        for delay in COLLECT_RETRY_DELAYS {
+            let err = match &result {
+                Ok(()) => break,
+                Err(err) => err,
             };
-            let info = ContInfo {
-                pod: pod,
-                name: name,
-                state: state,
-                uptime: uptime,
+            warn!(error = %err, retry_in = ?delay, "Collection failed, retrying");
+            time::sleep(delay).await;
+            result = self.collect_exclusive().await;
+        }
+        self.track_failure(result.is_err()).await;
+        match result {
+            Ok(()) => {
+                self.metrics
+                    .last_collection_timestamp
+                    .set(Utc::now().timestamp() as f64);
+                Ok(())
+            }
+            Err(err) => {
+                self.metrics.scrape_errors.inc();
+                Err(err)
+            }
+        }
+    }
+
+    /// Concurrent scrapes, the events and the remote write would otherwise interleave the resets
+    /// and updates of their collections.
+    async fn collect_exclusive(&self) -> Result<()> {
+        let _collection = self.collection.lock().await;
+        self.collect().await
+    }
+
+    /// Gathers between collections, so never from metrics that are reset but not filled yet.
+    async fn gather(&self, config: &ServerConfig) -> Vec<MetricFamily> {
+        let _collection = self.collection.lock().await;
+        gather(&self.metrics, config)
+    }
+
+    /// Requests the stats of all containers, `None` when the stats are disabled or Podman
+    /// reported none.
+    ///
+    /// The report of all containers is parsed as a whole, `stats_stream` would only stream one
+    /// such report per interval rather than the containers of one report.
+    async fn stats(&self) -> Result<Option<Vec<LibpodContainerStats>>> {
+        if !self.config.collects(MetricGroup::ContainerStats) {
+            return Ok(None);
+        }
+        let resp = self
+            .podman()
+            .containers()
+            .stats(&Default::default())
+            .await
+            .map_err(|e| anyhow!("Stats request: {}", e))?;
+
+        match resp.error {
+            Value::Null => (),
+            err => error!(error = %err, "Stats API error"),
+        };
+        Ok(resp.stats)
+    }
+
+    async fn collect(&self) -> Result<()> {
+        // The list and the stats are independent requests, so they run concurrently and are
+        // correlated by the container ID afterwards.
+        let ((containers, filtered), stats) = tokio::try_join!(self.containers(), self.stats())?;
+        let listed: Vec<(&str, &str)> = containers
+            .values()
+            .map(|cont| (cont.pod.as_deref().unwrap_or(""), cont.name.as_str()))
+            .collect();
+        self.metrics.retain_containers(&listed);
+        if self.config.collects(MetricGroup::ImageInfo) {
+            if let Err(err) = self.update_system_df().await {
+                warn!(error = %err, "System df collection failed");
+            }
+        }
+        if self.config.collects(MetricGroup::PodInfo) {
+            if let Err(err) = self.update_pods().await {
+                warn!(error = %err, "Pods collection failed");
+            }
+        }
+        if self.config.collects(MetricGroup::SecretInfo) {
+            if let Err(err) = self.update_secrets().await {
+                warn!(error = %err, "Secrets collection failed");
+            }
+            self.update_secret_info(&containers);
+        }
+        self.metrics.container_total.set(containers.len() as f64);
+        self.metrics
+            .container_name_collisions
+            .set(count_name_collisions(&containers) as f64);
+
+        if self.config.collects(MetricGroup::PodInfo) {
+            let pods = count_containers_per_pod(&containers);
+            for (pod, cnt) in pods.into_iter() {
+                self.metrics
+                    .container_count
+                    .with_label_values(&[&pod])
+                    .set(cnt as f64);
+            }
+            self.metrics.pod_running_containers.reset();
+            self.metrics.pod_stopped_containers.reset();
+            for (pod, (running, stopped)) in count_pod_containers_by_state(&containers) {
+                self.metrics
+                    .pod_running_containers
+                    .with_label_values(&[&pod])
+                    .set(running as f64);
+                self.metrics
+                    .pod_stopped_containers
+                    .with_label_values(&[&pod])
+                    .set(stopped as f64);
+            }
+        }
+        if self.config.collects(MetricGroup::ContainerInfo) {
+            let image_digests = match self.image_digests(&containers).await {
+                Ok(digests) => digests,
+                Err(err) => {
+                    warn!(error = %err, "Images collection failed");
+                    HashMap::new()
+                }
             };
-            result.insert(id, info);
+            self.update_info(&containers, &image_digests);
+            self.update_config_info(&containers);
+        }
+        if self.config.collects(MetricGroup::NetworkInfo) {
+            self.update_network_info(&containers);
+        }
+        if self.config.collects(MetricGroup::VolumeInfo) {
+            self.update_volume_info(&containers);
+        }
+        if self.config.collects(MetricGroup::SystemdUnits) {
+            if let Err(err) = self.update_systemd_units(&containers).await {
+                warn!(error = %err, "Systemd units collection failed");
+            }
+        }
+        if self.config.count_starts {
+            self.update_start_counts(&containers);
+        }
+        if self.config.collect_started_at {
+            self.metrics.container_started_at.reset();
+            for cont in containers.values() {
+                let pod = cont.pod.as_deref().unwrap_or("");
+                if let Some(started_at) = cont.started_at {
+                    self.metrics
+                        .container_started_at
+                        .with_label_values(&[pod, &cont.name])
+                        .set(started_at as f64);
+                }
+            }
+        }
+
+        // The stats API reads the cgroups of every container, which is the most expensive
+        // part of the collection. Without it the state comes from the container list.
+        let collect_state = self.config.collects(MetricGroup::ContainerState);
+        if collect_state {
+            // Set for every listed container, the stats only cover the running ones.
+            self.metrics.container_running.reset();
+            for cont in containers.values() {
+                let pod = cont.pod.as_deref().unwrap_or("");
+                let running = if cont.state == 1 { 1.0 } else { 0.0 };
+                self.metrics
+                    .container_running
+                    .with_label_values(&[pod, &cont.name])
+                    .set(running);
+            }
+        }
+        if !self.config.collects(MetricGroup::ContainerStats) {
+            for cont in containers.values().filter(|_| collect_state) {
+                let pod = cont.pod.as_deref().unwrap_or("");
+                self.metrics
+                    .container_state
+                    .with_label_values(&[pod, &cont.name])
+                    .set(cont.state as f64);
+            }
+            return Ok(());
+        }
+        let stats = match stats {
+            Some(stats) => stats,
+            None => return Ok(()),
+        };
+        self.update_stats(&containers, &filtered, stats, collect_state)
+            .await;
+        Ok(())
+    }
+
+    fn update_info(
+        &self,
+        containers: &HashMap<String, ContInfo>,
+        image_digests: &HashMap<String, Option<String>>,
+    ) {
+        self.metrics.container_info.reset();
+        for (id, cont) in containers.iter() {
+            let image_id = image_id(&cont.image_id, self.config.full_image_id);
+            let mut values = vec![
+                cont.pod.as_deref().unwrap_or(""),
+                cont.name.as_str(),
+                id.as_str(),
+                cont.image.as_str(),
+                image_id.as_str(),
+                image_digests
+                    .get(id_digits(&cont.image_id))
+                    .and_then(Option::as_deref)
+                    .unwrap_or(""),
+                cont.security.as_ref().map_or("", |s| s.user.as_str()),
+                cont.network_mode.as_str(),
+                cont.user_namespace.as_str(),
+                cont.cgroup_version,
+                cont.labels
+                    .get(COMPOSE_PROJECT_LABEL)
+                    .map_or("", String::as_str),
+                cont.labels
+                    .get(COMPOSE_SERVICE_LABEL)
+                    .map_or("", String::as_str),
+                if cont.is_infra { "true" } else { "false" },
+            ];
+            for key in self.config.expose_labels.iter() {
+                values.push(cont.labels.get(key).map_or("", String::as_str));
+            }
+            for var in self.config.expose_env.iter() {
+                values.push(cont.env.get(var).map_or("", String::as_str));
+            }
+            self.metrics
+                .container_info
+                .with_label_values(&values)
+                .set(1.0);
+        }
+
+        if let Some(prefix) = self.config.dynamic_label_prefix.as_deref() {
+            *self.metrics.dynamic_info_labels.lock().unwrap() = containers
+                .iter()
+                .map(|(id, cont)| (id.clone(), dynamic_labels(&cont.labels, prefix)))
+                .collect();
+        }
+    }
+
+    /// The DBus connection is opened on first use and again after a failed request.
+    async fn update_systemd_units(&self, containers: &HashMap<String, ContInfo>) -> Result<()> {
+        let client = {
+            let mut client = self.systemd.lock().await;
+            match client.as_ref() {
+                Some(c) => c.clone(),
+                None => client.insert(SystemdClient::connect().await?).clone(),
+            }
+        };
+        let units: Vec<(&ContInfo, String)> = containers
+            .values()
+            .map(|cont| {
+                let unit = cont
+                    .labels
+                    .get(SYSTEMD_UNIT_LABEL)
+                    .cloned()
+                    .unwrap_or_else(|| format!("container-{}.service", cont.name));
+                (cont, unit)
+            })
+            .collect();
+        let names: Vec<String> = units.iter().map(|(_, unit)| unit.clone()).collect();
+        let states = match client.unit_states(&names).await {
+            Ok(states) => states,
+            Err(err) => {
+                *self.systemd.lock().await = None;
+                return Err(err);
+            }
+        };
+        self.metrics.systemd_unit_active_state.reset();
+        for (cont, unit) in units.iter() {
+            if let Some(state) = states.get(unit) {
+                let pod = cont.pod.as_deref().unwrap_or("");
+                self.metrics
+                    .systemd_unit_active_state
+                    .with_label_values(&[pod, &cont.name, unit])
+                    .set(systemd::active_state_value(state));
+            }
+        }
+        Ok(())
+    }
+
+    fn update_network_info(&self, containers: &HashMap<String, ContInfo>) {
+        self.metrics.container_network_info.reset();
+        for cont in containers.values() {
+            let pod = cont.pod.as_deref().unwrap_or("");
+            for network in cont.networks.iter() {
+                self.metrics
+                    .container_network_info
+                    .with_label_values(&[pod, &cont.name, network])
+                    .set(1.0);
+            }
+        }
+    }
+
+    fn update_volume_info(&self, containers: &HashMap<String, ContInfo>) {
+        self.metrics.container_volume_mount_info.reset();
+        for cont in containers.values() {
+            let pod = cont.pod.as_deref().unwrap_or("");
+            for volume in cont.volumes.iter().flatten() {
+                let read_only = if volume.read_only { "true" } else { "false" };
+                self.metrics
+                    .container_volume_mount_info
+                    .with_label_values(&[
+                        pod,
+                        &cont.name,
+                        &volume.name,
+                        &volume.destination,
+                        read_only,
+                    ])
+                    .set(1.0);
+            }
+        }
+    }
+
+    /// Containers already started before they were first collected start from their inspect
+    /// restart count plus the first start, if `start_count_from_inspect` is set.
+    fn update_start_counts(&self, containers: &HashMap<String, ContInfo>) {
+        let mut start_counts = self.start_counts.lock().unwrap();
+        start_counts.retain(|id, _| containers.contains_key(id));
+        for (id, cont) in containers.iter() {
+            let count = start_counts.entry(id.clone()).or_default();
+            let base = *count.base.get_or_insert_with(|| {
+                if !self.config.start_count_from_inspect {
+                    return 0;
+                }
+                let restarts = cont.restart_count.unwrap_or(0).max(0) as u64;
+                let starts = restarts + cont.started_at.is_some() as u64;
+                // Starts already counted from events are included in the inspect counts.
+                starts.saturating_sub(count.events)
+            });
+            let pod = cont.pod.as_deref().unwrap_or("");
+            self.metrics
+                .container_start_count
+                .set_total(&[pod, &cont.name], (base + count.events) as f64);
+        }
+    }
+
+    fn update_secret_info(&self, containers: &HashMap<String, ContInfo>) {
+        self.metrics.container_secret_info.reset();
+        for cont in containers.values() {
+            let pod = cont.pod.as_deref().unwrap_or("");
+            for secret in cont.secrets.iter().flatten() {
+                self.metrics
+                    .container_secret_info
+                    .with_label_values(&[pod, &cont.name, &secret.name, &secret.id])
+                    .set(1.0);
+            }
         }
-        Ok(result)
     }
 
-    async fn update_stat(&self) -> Result<()> {
-        let containers = self.containers().await?;
-        let resp = self
-            .podman
-            .containers()
-            .stats(&Default::default())
-            .await
-            .map_err(|e| anyhow!("Stats request: {}", e))?;
+    /// The info metrics (value `1`) read from the container configuration: restart policy,
+    /// devices and capabilities.
+    fn update_config_info(&self, containers: &HashMap<String, ContInfo>) {
+        self.metrics.container_restart_policy_info.reset();
+        for cont in containers.values() {
+            let pod = cont.pod.as_deref().unwrap_or("");
+            if let Some(restart) = cont.restart_policy.as_ref() {
+                self.metrics
+                    .container_restart_policy_info
+                    .with_label_values(&[
+                        pod,
+                        &cont.name,
+                        &restart.policy,
+                        &restart.max_retry_count.to_string(),
+                    ])
+                    .set(1.0);
+            }
+        }
 
-        match resp.error {
-            Value::Null => (),
-            err @ _ => eprintln!("ApiError: {}", err),
-        };
-        let stats = match resp.stats {
-            Some(stats) => stats,
-            None => return Ok(()),
-        };
+        self.metrics.container_oom_score_adj.reset();
+        for cont in containers.values() {
+            let pod = cont.pod.as_deref().unwrap_or("");
+            if let Some(oom_score_adj) = cont.oom_score_adj {
+                self.metrics
+                    .container_oom_score_adj
+                    .with_label_values(&[pod, &cont.name])
+                    .set(oom_score_adj as f64);
+            }
+        }
 
-        CONTAINER_TOTAL.set(containers.len() as f64);
+        self.metrics.container_ulimit_soft.reset();
+        self.metrics.container_ulimit_hard.reset();
+        for cont in containers.values() {
+            let pod = cont.pod.as_deref().unwrap_or("");
+            for ulimit in cont.ulimits.iter() {
+                let labels = [pod, cont.name.as_str(), ulimit.kind.as_str()];
+                self.metrics
+                    .container_ulimit_soft
+                    .with_label_values(&labels)
+                    .set(ulimit.soft as f64);
+                self.metrics
+                    .container_ulimit_hard
+                    .with_label_values(&labels)
+                    .set(ulimit.hard as f64);
+            }
+        }
 
-        let mut pods: HashMap<String, usize> = HashMap::new();
-        for (_, cont) in containers.iter() {
-            if let Some(pod) = cont.pod.clone() {
-                let container_cnt = pods.entry(pod).or_insert(0);
-                *container_cnt += 1;
+        self.metrics.container_device_access_info.reset();
+        for cont in containers.values() {
+            let pod = cont.pod.as_deref().unwrap_or("");
+            for device in cont.security.iter().flat_map(|s| s.devices.iter()) {
+                self.metrics
+                    .container_device_access_info
+                    .with_label_values(&[pod, &cont.name, &device.path, &device.permissions])
+                    .set(1.0);
             }
         }
-        for (pod, cnt) in pods.into_iter() {
-            CONTAINER_COUNT.with_label_values(&[&pod]).set(cnt as f64);
+
+        self.metrics.container_capability_info.reset();
+        for cont in containers.values() {
+            let pod = cont.pod.as_deref().unwrap_or("");
+            let security = match cont.security.as_ref() {
+                Some(security) => security,
+                None => continue,
+            };
+            let caps = security.cap_add.iter().map(|cap| (cap, "true"));
+            for (cap, added) in caps.chain(security.cap_drop.iter().map(|cap| (cap, "false"))) {
+                self.metrics
+                    .container_capability_info
+                    .with_label_values(&[pod, &cont.name, cap, added])
+                    .set(1.0);
+            }
         }
+    }
 
+    async fn update_stats(
+        &self,
+        containers: &HashMap<String, ContInfo>,
+        filtered: &HashSet<String>,
+        stats: Vec<LibpodContainerStats>,
+        collect_state: bool,
+    ) {
+        debug!(
+            containers = containers.len(),
+            stats = stats.len(),
+            "Collected container stats"
+        );
+        let extended_stats = self.extended_stats_supported();
+        let host_memory = self.host_info().await.mem_total;
+
+        let mut mem_usage_total = 0.0;
+        let mut cpu_total = 0.0;
+        let mut pod_stats: HashMap<String, PodStats> = HashMap::new();
         for stat in stats.into_iter() {
             let cont_id = match stat.container_id.as_ref() {
                 Some(id) => id,
@@ -230,99 +2160,1478 @@ impl Collector {
             };
             let cont = match containers.get(cont_id) {
                 Some(s) => s,
-                None => continue,
+                // Left out on purpose, so not a correlation miss.
+                None if filtered.contains(cont_id) => continue,
+                None => {
+                    self.metrics.stat_correlation_misses.inc();
+                    debug!(container_id = %cont_id, "Container stats without a listed container");
+                    continue;
+                }
             };
             let pod = match cont.pod.as_ref() {
                 Some(p) => p,
                 None => "",
             };
             let name = &cont.name;
+            mem_usage_total += stat.mem_usage.unwrap_or(0) as f64;
+            cpu_total += stat.CPU.unwrap_or(0.0) as f64;
+            if self.config.collect_pod_stats && !pod.is_empty() {
+                pod_stats.entry(pod.to_string()).or_default().add(&stat);
+            }
 
-            CONTAINER_STATE
-                .with_label_values(&[pod, name])
-                .set(cont.state as f64);
-            CONTAINER_UPTIME
+            if collect_state {
+                self.metrics
+                    .container_state
+                    .with_label_values(&[pod, name])
+                    .set(cont.state as f64);
+            }
+            self.metrics
+                .container_uptime
                 .with_label_values(&[pod, name])
-                .set(stat.up_time.unwrap_or(0) as f64);
-            CONTAINER_UPTIME_CALC
+                .set(uptime_nanos(stat.up_time, cont, Utc::now().timestamp()) as f64);
+            self.metrics
+                .container_uptime_calc
                 .with_label_values(&[pod, name])
                 .set(cont.uptime as f64);
-            CONTAINER_SYSTEM_NANO
+            self.metrics
+                .container_system_nano
                 .with_label_values(&[pod, name])
                 .set(stat.system_nano.unwrap_or(0) as f64);
 
-            CONTAINER_PIDS
-                .with_label_values(&[pod, name])
-                .set(stat.pi_ds.unwrap_or(0) as f64);
-            CONTAINER_AVG_CPU
+            if extended_stats {
+                self.metrics
+                    .container_pids
+                    .with_label_values(&[pod, name])
+                    .set(stat.pi_ds.unwrap_or(0) as f64);
+            }
+            self.metrics
+                .container_avg_cpu
                 .with_label_values(&[pod, name])
                 .set(stat.avg_cpu.unwrap_or(0.0) as f64);
-            CONTAINER_CPU
+            self.metrics
+                .container_cpu
                 .with_label_values(&[pod, name])
                 .set(stat.CPU.unwrap_or(0.0) as f64);
-            CONTAINER_CPU_NANO
+            if let Some(cpu_count) = cont.cpu_count {
+                self.metrics
+                    .container_cpu_count
+                    .with_label_values(&[pod, name])
+                    .set(cpu_count as f64);
+            }
+            self.metrics
+                .container_cpu_nano
                 .with_label_values(&[pod, name])
                 .set(stat.cpu_nano.unwrap_or(0) as f64);
-            CONTAINER_CPU_SYSTEM_NANO
+            self.metrics
+                .container_cpu_system_nano
                 .with_label_values(&[pod, name])
                 .set(stat.cpu_system_nano.unwrap_or(0) as f64);
+            // cgroup v2 has no per-CPU accounting, Podman then omits per_cpu.
+            for (core, usage) in stat.per_cpu.iter().flatten().enumerate() {
+                self.metrics
+                    .container_cpu_per_core
+                    .with_label_values(&[pod, name, &core.to_string()])
+                    .set(*usage as f64);
+            }
+            let (cpu_user, cpu_kernel) = cpu_user_kernel(&stat);
+            self.metrics
+                .container_cpu_user_nano
+                .with_label_values(&[pod, name])
+                .set(cpu_user as f64);
+            self.metrics
+                .container_cpu_kernel_nano
+                .with_label_values(&[pod, name])
+                .set(cpu_kernel as f64);
+            if let Some(throttling) = cont.cgroup_path.as_deref().and_then(cpu_throttling) {
+                self.metrics
+                    .container_cpu_throttled_periods
+                    .set_total(&[pod, name], throttling.periods);
+                self.metrics
+                    .container_cpu_throttled_seconds
+                    .set_total(&[pod, name], throttling.seconds);
+            }
 
-            CONTAINER_MEM_USAGE
+            self.metrics
+                .container_mem_usage
                 .with_label_values(&[pod, name])
                 .set(stat.mem_usage.unwrap_or(0) as f64);
-            CONTAINER_MEM_LIMIT
+            let mem_limit = stat.mem_limit.unwrap_or(0);
+            let mem_unlimited = memory_unlimited(mem_limit, cont.memory_limit, host_memory);
+            self.metrics
+                .container_mem_limit
                 .with_label_values(&[pod, name])
-                .set(stat.mem_limit.unwrap_or(0) as f64);
-            CONTAINER_MEM_PERC
+                .set(if mem_unlimited { 0.0 } else { mem_limit as f64 });
+            self.metrics
+                .container_mem_unlimited
+                .with_label_values(&[pod, name])
+                .set(mem_unlimited as u8 as f64);
+            self.metrics
+                .container_mem_perc
                 .with_label_values(&[pod, name])
                 .set(stat.mem_perc.unwrap_or(0.0) as f64);
 
-            CONTAINER_NET_INP
-                .with_label_values(&[pod, name])
-                .set(stat.net_input.unwrap_or(0) as f64);
-            CONTAINER_NET_OUT
-                .with_label_values(&[pod, name])
-                .set(stat.net_output.unwrap_or(0) as f64);
-            CONTAINER_BL_INP
+            if extended_stats {
+                self.metrics
+                    .container_net_inp
+                    .with_label_values(&[pod, name])
+                    .set(stat.net_input.unwrap_or(0) as f64);
+                self.metrics
+                    .container_net_out
+                    .with_label_values(&[pod, name])
+                    .set(stat.net_output.unwrap_or(0) as f64);
+
+                let (net_input, net_output) = network_totals(&stat);
+                self.metrics
+                    .container_net_total_inp
+                    .with_label_values(&[pod, name])
+                    .set(net_input as f64);
+                self.metrics
+                    .container_net_total_out
+                    .with_label_values(&[pod, name])
+                    .set(net_output as f64);
+            }
+            if self.config.collect_fds {
+                if let Some(fds) = cont.pid.and_then(open_fds) {
+                    self.metrics
+                        .container_open_fds
+                        .with_label_values(&[pod, name])
+                        .set(fds as f64);
+                }
+            }
+            if self.config.collect_interface_packets {
+                for packets in cont.pid.map(interface_packets).into_iter().flatten() {
+                    let interface = packets.interface.as_str();
+                    self.metrics
+                        .container_net_if_rx_packets
+                        .with_label_values(&[pod, name, interface])
+                        .set(packets.rx);
+                    self.metrics
+                        .container_net_if_tx_packets
+                        .with_label_values(&[pod, name, interface])
+                        .set(packets.tx);
+                }
+            }
+            self.metrics
+                .container_bl_inp
                 .with_label_values(&[pod, name])
                 .set(stat.block_input.unwrap_or(0) as f64);
-            CONTAINER_BL_OUT
+            self.metrics
+                .container_bl_out
                 .with_label_values(&[pod, name])
                 .set(stat.block_output.unwrap_or(0) as f64);
+            self.metrics
+                .container_io_read
+                .set_total(&[pod, name], stat.block_input.unwrap_or(0) as f64);
+            self.metrics
+                .container_io_write
+                .set_total(&[pod, name], stat.block_output.unwrap_or(0) as f64);
+            if let Some(ops) = cont.cgroup_path.as_deref().and_then(blkio_ops) {
+                self.metrics
+                    .container_blkio_read_ops
+                    .set_total(&[pod, name], ops.read);
+                self.metrics
+                    .container_blkio_write_ops
+                    .set_total(&[pod, name], ops.write);
+            }
+
+            self.metrics
+                .container_mounts
+                .with_label_values(&[pod, name])
+                .set(cont.mounts as f64);
+            let volumes = cont
+                .volumes
+                .as_ref()
+                .filter(|_| self.config.collects(MetricGroup::VolumeInfo));
+            if let Some(volumes) = volumes {
+                self.metrics
+                    .container_volumes_mounted
+                    .with_label_values(&[pod, name])
+                    .set(volumes.len() as f64);
+            }
+            let secrets = cont
+                .secrets
+                .as_ref()
+                .filter(|_| self.config.collects(MetricGroup::SecretInfo));
+            if let Some(secrets) = secrets {
+                self.metrics
+                    .container_secret_count
+                    .with_label_values(&[pod, name])
+                    .set(secrets.len() as f64);
+            }
+            if let Some(rw_size) = cont.rw_size {
+                self.metrics
+                    .container_rw_size
+                    .with_label_values(&[pod, name])
+                    .set(rw_size as f64);
+            }
+            if let Some(rootfs_size) = cont.rootfs_size {
+                self.metrics
+                    .container_rootfs_size
+                    .with_label_values(&[pod, name])
+                    .set(rootfs_size as f64);
+            }
+
+            if let Some(security) = cont.security.as_ref() {
+                self.metrics
+                    .container_privileged
+                    .with_label_values(&[pod, name])
+                    .set(security.privileged as u8 as f64);
+                self.metrics
+                    .container_readonly_rootfs
+                    .with_label_values(&[pod, name])
+                    .set(security.readonly_rootfs as u8 as f64);
+                self.metrics
+                    .container_run_as_root
+                    .with_label_values(&[pod, name])
+                    .set(security.runs_as_root() as u8 as f64);
+            }
+        }
+        self.metrics.containers_mem_usage_total.set(mem_usage_total);
+        self.metrics.containers_cpu_total.set(cpu_total);
+        if self.config.collect_pod_stats {
+            self.update_pod_stats(pod_stats);
+        }
+    }
+
+    fn update_pod_stats(&self, pod_stats: HashMap<String, PodStats>) {
+        self.metrics.pod_cpu.reset();
+        self.metrics.pod_mem_usage.reset();
+        self.metrics.pod_net_input.reset();
+        self.metrics.pod_net_output.reset();
+        for (pod, stats) in pod_stats.iter() {
+            let pod = pod.as_str();
+            self.metrics
+                .pod_cpu
+                .with_label_values(&[pod])
+                .set(stats.cpu);
+            self.metrics
+                .pod_mem_usage
+                .with_label_values(&[pod])
+                .set(stats.mem_usage);
+            self.metrics
+                .pod_net_input
+                .with_label_values(&[pod])
+                .set(stats.net_input);
+            self.metrics
+                .pod_net_output
+                .with_label_values(&[pod])
+                .set(stats.net_output);
+        }
+    }
+}
+
+/// Bounds the collection by the scrape timeout Prometheus sends, minus a margin for encoding
+/// and the network, and by `max` (`--max-scrape-timeout`).
+fn scrape_timeout(headers: &HeaderMap, max: Option<f64>) -> Option<Duration> {
+    let requested = headers
+        .get("X-Prometheus-Scrape-Timeout-Seconds")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<f64>().ok())
+        .filter(|secs| secs.is_finite() && *secs > 0.0)
+        .map(|secs| (secs - SCRAPE_TIMEOUT_MARGIN).max(SCRAPE_TIMEOUT_MARGIN));
+    let timeout = match (requested, max) {
+        (Some(requested), Some(max)) => requested.min(max),
+        (requested, max) => requested.or(max)?,
+    };
+    Some(Duration::from_secs_f64(timeout))
+}
+
+/// Checks whether the client accepts a gzip-encoded response (`gzip;q=0` is a refusal).
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|encoding| {
+            let mut params = encoding.split(';').map(str::trim);
+            let name = params.next().unwrap_or("");
+            let refused = params
+                .any(|p| p.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0));
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+        })
+}
+
+fn gzip(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Checks whether the `Accept` header ranks OpenMetrics at least as high as any other format.
+fn prefers_openmetrics(headers: &HeaderMap) -> bool {
+    let mut openmetrics_q: f32 = 0.0;
+    let mut other_q: f32 = 0.0;
+    let media_ranges = headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+    for media_range in media_ranges {
+        let mut params = media_range.split(';').map(str::trim);
+        let media_type = params.next().unwrap_or("");
+        let q = params
+            .find_map(|p| p.strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if media_type.eq_ignore_ascii_case("application/openmetrics-text") {
+            openmetrics_q = openmetrics_q.max(q);
+        } else {
+            other_q = other_q.max(q);
+        }
+    }
+    openmetrics_q > 0.0 && openmetrics_q >= other_q
+}
+
+/// Checks whether the `Accept` header asks for JSON, e.g. from an uptime checker.
+fn accepts_json(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_range| {
+            let mut params = media_range.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or("");
+            let refused = params
+                .any(|p| p.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0));
+            media_type.eq_ignore_ascii_case("application/json") && !refused
+        })
+}
+
+/// Plain text for Prometheus, `{"error": ..., "podman_up": 0|1}` for clients asking for JSON.
+fn error_response(
+    headers: &HeaderMap,
+    status: StatusCode,
+    message: &str,
+    podman_up: bool,
+) -> Response<Body> {
+    if accepts_json(headers) {
+        let body = serde_json::json!({ "error": message, "podman_up": podman_up as u8 });
+        return response(status, "application/json", body.to_string().into_bytes());
+    }
+    let body = format!("{}\n", message).into_bytes();
+    response(status, "text/plain; charset=utf-8", body)
+}
+
+fn gather(metrics: &Metrics, config: &ServerConfig) -> Vec<MetricFamily> {
+    let mut metric_families = metrics.registry.gather();
+    if config.exporter_metrics {
+        metric_families.extend(metrics.exporter_registry.gather());
+        metric_families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+    }
+    let dynamic_info_labels = metrics.dynamic_info_labels.lock().unwrap();
+    for family in metric_families.iter_mut() {
+        if config.metric_naming == MetricNaming::Strict {
+            if let Some(name) = strict_name(family.get_name()) {
+                family.set_name(name.to_string());
+            }
+        }
+        let is_info = family.get_name() == "podman_container_info";
+        for metric in family.mut_metric().iter_mut() {
+            if is_info {
+                add_dynamic_labels(metric, &dynamic_info_labels);
+            }
+            for label in config.external_labels.iter() {
+                // Labels of the metric itself win over external ones.
+                if metric
+                    .get_label()
+                    .iter()
+                    .any(|l| l.get_name() == label.name)
+                {
+                    continue;
+                }
+                let mut pair = LabelPair::new();
+                pair.set_name(label.name.clone());
+                pair.set_value(label.value.clone());
+                metric.mut_label().push(pair);
+            }
+        }
+    }
+    metric_families
+}
+
+/// Collects (unless the events keep the metrics up to date) and pushes the metrics every
+/// `interval`, next to the HTTP server.
+async fn remote_write(
+    collector: Arc<Collector>,
+    config: Arc<ServerConfig>,
+    writer: RemoteWriter,
+    interval: Duration,
+) {
+    let mut ticks = time::interval(interval);
+    loop {
+        ticks.tick().await;
+        if !config.event_driven {
+            if let Err(err) = collector.update_stat().await {
+                warn!(error = %err, "Collection failed, pushing the last metrics");
+            }
+        }
+        let families = collector.gather(&config).await;
+        if let Err(err) = writer.send(&families, Utc::now().timestamp_millis()).await {
+            warn!(error = %err, "Remote write failed");
         }
-        Ok(())
     }
 }
 
-async fn serve_req(_req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
-    COLLECTOR.update_stat().await.unwrap();
+/// The static labels of `podman_container_info` win over dynamic ones with the same name.
+fn add_dynamic_labels(metric: &mut Metric, labels: &HashMap<String, Vec<(String, String)>>) {
+    let id = match metric.get_label().iter().find(|l| l.get_name() == "id") {
+        Some(label) => label.get_value().to_string(),
+        None => return,
+    };
+    for (name, value) in labels.get(&id).into_iter().flatten() {
+        if metric.get_label().iter().any(|l| l.get_name() == name) {
+            continue;
+        }
+        let mut pair = LabelPair::new();
+        pair.set_name(name.clone());
+        pair.set_value(value.clone());
+        metric.mut_label().push(pair);
+    }
+}
 
-    let encoder = TextEncoder::new();
-    let metric_families = prometheus::gather();
+/// An encoder error is logged and counted in `errors`, the families encoded up to it are still
+/// returned so that a single bad family doesn't fail the whole scrape.
+fn encode<E: Encoder>(encoder: &E, metric_families: &[MetricFamily], errors: &Counter) -> Vec<u8> {
     let mut buffer = vec![];
-    encoder.encode(&metric_families, &mut buffer).unwrap();
+    if let Err(err) = encoder.encode(metric_families, &mut buffer) {
+        errors.inc();
+        error!(error = %err, encoded_bytes = buffer.len(), "Metrics encoding failed");
+    }
+    buffer
+}
+
+fn response(status: StatusCode, content_type: &'static str, body: Vec<u8>) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
+
+async fn serve_req(
+    req: Request<Body>,
+    collector: Arc<Collector>,
+    config: &ServerConfig,
+) -> Result<Response<Body>, hyper::Error> {
+    if req.uri().path() != config.metrics_path {
+        let body = b"Not Found\n".to_vec();
+        return Ok(response(
+            StatusCode::NOT_FOUND,
+            "text/plain; charset=utf-8",
+            body,
+        ));
+    }
+    collector.metrics.scrapes.inc();
+    if !config.event_driven {
+        let collection = match scrape_timeout(req.headers(), config.max_scrape_timeout) {
+            Some(timeout) => match time::timeout(timeout, collector.update_stat()).await {
+                Ok(collection) => collection,
+                Err(_) => {
+                    warn!(timeout = ?timeout, "Collection timed out");
+                    let message = format!("Collection timed out after {:?}", timeout);
+                    return Ok(error_response(
+                        req.headers(),
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        &message,
+                        false,
+                    ));
+                }
+            },
+            None => collector.update_stat().await,
+        };
+        if let Err(err) = collection {
+            // Without a successful collection there are no metrics to fall back to.
+            if collector.metrics.last_collection_timestamp.get() == 0.0 {
+                error!(error = %err, "Collection failed");
+                let message = format!("Podman collection failed: {}", err);
+                return Ok(error_response(
+                    req.headers(),
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    &message,
+                    false,
+                ));
+            }
+            error!(error = %err, "Collection failed, serving the last collected metrics");
+        }
+    }
+
+    let metric_families = collector.gather(config).await;
+    let encode_errors = &collector.metrics.encode_errors;
+    let (mut buffer, content_type) = if prefers_openmetrics(req.headers()) {
+        let buffer = encode(&OpenMetricsEncoder::new(), &metric_families, encode_errors);
+        (buffer, OPENMETRICS_FORMAT)
+    } else {
+        let buffer = encode(&TextEncoder::new(), &metric_families, encode_errors);
+        (buffer, TEXT_FORMAT)
+    };
+
+    if buffer.is_empty() && !metric_families.is_empty() {
+        return Ok(error_response(
+            req.headers(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Metrics encoding failed",
+            true,
+        ));
+    }
+    let mut gzipped = false;
+    if accepts_gzip(req.headers()) {
+        match gzip(&buffer) {
+            Ok(compressed) => {
+                buffer = compressed;
+                gzipped = true;
+            }
+            Err(err) => warn!(error = %err, "Gzip compression failed, serving uncompressed"),
+        }
+    }
+    let mut response = response(StatusCode::OK, content_type, buffer);
+    if gzipped {
+        response
+            .headers_mut()
+            .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    }
+
+    Ok(response)
+}
 
-    let response = Response::builder()
-        .status(200)
-        .header(CONTENT_TYPE, encoder.format_type())
-        .body(Body::from(buffer))
-        .unwrap();
+/// Logs every request with `--access-log`, e.g. to tell which Prometheus replica scrapes.
+async fn serve_logged(
+    req: Request<Body>,
+    remote_addr: SocketAddr,
+    collector: Arc<Collector>,
+    config: Arc<ServerConfig>,
+    rejected: bool,
+) -> Result<Response<Body>, hyper::Error> {
+    if !config.access_log {
+        return serve_limited(req, collector, &config, rejected).await;
+    }
+    let started = Instant::now();
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let response = serve_limited(req, collector, &config, rejected).await?;
+    info!(
+        method = %method,
+        path = %path,
+        remote_addr = %remote_addr,
+        status = response.status().as_u16(),
+        duration = ?started.elapsed(),
+        "HTTP request"
+    );
+    Ok(response)
+}
 
+/// Connections beyond `--max-connections` are `rejected`, every request on them gets a `429`
+/// and the connection is closed so that the client retries on a new one.
+async fn serve_limited(
+    req: Request<Body>,
+    collector: Arc<Collector>,
+    config: &ServerConfig,
+    rejected: bool,
+) -> Result<Response<Body>, hyper::Error> {
+    if !rejected {
+        return serve_req(req, collector, config).await;
+    }
+    let mut response = error_response(
+        req.headers(),
+        StatusCode::TOO_MANY_REQUESTS,
+        "Too many concurrent connections",
+        true,
+    );
+    response
+        .headers_mut()
+        .insert(CONNECTION, HeaderValue::from_static("close"));
     Ok(response)
 }
 
+/// Picks the Podman socket when `--podman` isn't set: `$PODMAN_SOCKET`, then the rootless
+/// socket in `$XDG_RUNTIME_DIR`, then the Podman machine socket on macOS, then the rootful
+/// socket.
+fn detect_podman_socket() -> String {
+    if let Ok(socket) = env::var("PODMAN_SOCKET") {
+        if !socket.is_empty() {
+            info!(socket = %socket, "Using Podman socket from PODMAN_SOCKET");
+            if socket.contains("://") {
+                return socket;
+            }
+            return format!("unix://{}", socket);
+        }
+    }
+    let candidates = [
+        env::var("XDG_RUNTIME_DIR")
+            .ok()
+            .map(|dir| Path::new(&dir).join("podman/podman.sock")),
+        env::var("HOME").ok().map(|home| {
+            Path::new(&home).join(".local/share/containers/podman/machine/qemu/podman.sock")
+        }),
+    ];
+    for socket in candidates.into_iter().flatten() {
+        if socket.exists() {
+            info!(socket = %socket.display(), "Using rootless Podman socket");
+            return format!("unix://{}", socket.display());
+        }
+    }
+    info!(
+        socket = DEFAULT_PODMAN_SOCKET,
+        "Using rootful Podman socket"
+    );
+    format!("unix://{}", DEFAULT_PODMAN_SOCKET)
+}
+
+fn log_layer<S, W>(format: &LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Text => fmt::layer().with_writer(writer).with_ansi(ansi).boxed(),
+        LogFormat::Json => fmt::layer().json().with_writer(writer).boxed(),
+    }
+}
+
+fn log_file_appender(path: &Path, rotation: &LogRotation) -> Result<RollingFileAppender> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid log file path: {}", path.display()))?;
+    let directory = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let rotation = match rotation {
+        LogRotation::Minutely => Rotation::MINUTELY,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Never => Rotation::NEVER,
+    };
+    Ok(RollingFileAppender::new(rotation, directory, file_name))
+}
+
+fn init_logging(args: &AppArgs) -> Result<()> {
+    let filter = EnvFilter::try_new(&args.log_level)
+        .map_err(|e| anyhow!("Invalid log level {}: {}", args.log_level, e))?;
+    let stderr_layer = if args.no_stderr {
+        None
+    } else {
+        Some(log_layer(&args.log_format, io::stderr, true))
+    };
+    let file_layer = match args.log_file.as_ref() {
+        Some(path) => {
+            let appender = log_file_appender(path, &args.log_rotation)?;
+            Some(log_layer(&args.log_format, appender, false))
+        }
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(stderr_layer)
+        .with(file_layer)
+        .init();
+    Ok(())
+}
+
 #[tokio::main]
-async fn main() {
-    let addr = IpAddr::from_str(&ARGS.host).unwrap();
-    let host = (addr, ARGS.port).into();
-    println!("Listening on http://{}", host);
-    println!("Podman API {}", &ARGS.podman);
-
-    let serve_future = Server::bind(&host).serve(make_service_fn(|_| async {
-        Ok::<_, hyper::Error>(service_fn(serve_req))
-    }));
-
-    if let Err(err) = serve_future.await {
-        eprintln!("server error: {}", err);
+async fn main() -> Result<()> {
+    if let Some(path) = env::var_os(ssh::ASKPASS_FILE_ENV) {
+        ssh::askpass(Path::new(&path));
+    }
+    init_logging(&ARGS)?;
+    check_info_labels(&ARGS.expose_labels, &ARGS.expose_env)?;
+    let metrics = Arc::new(Metrics::new(&MetricsConfig::from_args(&ARGS))?);
+    metrics
+        .build_info
+        .with_label_values(&[
+            env!("CARGO_PKG_VERSION"),
+            env!("BUILD_DATE"),
+            env!("GIT_COMMIT"),
+            env!("RUSTC_VERSION"),
+        ])
+        .set(1.0);
+
+    info!(podman = %*PODMAN_URI, "Podman API");
+    let config = CollectorConfig::from_args(&ARGS);
+    let collector = match Collector::new(PODMAN_URI.as_str(), config, metrics.clone()) {
+        Ok(collector) => Arc::new(collector),
+        Err(err) => {
+            error!(error = %err, "Podman client setup failed");
+            process::exit(1)
+        }
+    };
+    run(&ARGS, metrics, collector).await
+}
+
+/// Runs the exporter until the server stops: prints the metrics once with `--print-once`,
+/// otherwise starts the background collections and serves the metrics.
+async fn run(args: &AppArgs, metrics: Arc<Metrics>, collector: Arc<Collector>) -> Result<()> {
+    match collector.negotiate_api_version().await {
+        Ok(version) if version < args.min_api_version => warn!(
+            api_version = %version,
+            min_api_version = %args.min_api_version,
+            "Podman API is older than required, PIDs and network metrics are disabled"
+        ),
+        Ok(version) => info!(api_version = %version, "Podman API version"),
+        Err(err) => warn!(error = %err, "Podman API version negotiation failed"),
+    }
+    let config = Arc::new(ServerConfig::from_args(args));
+    if args.print_once {
+        collector.update_stat().await?;
+        let buffer = encode(
+            &TextEncoder::new(),
+            &collector.gather(&config).await,
+            &metrics.encode_errors,
+        );
+        io::stdout()
+            .write_all(&buffer)
+            .map_err(|e| anyhow!("Write metrics: {}", e))?;
+        return Ok(());
+    }
+    if args.event_driven {
+        let poll_interval = Duration::from_secs(args.poll_interval);
+        let collector = collector.clone();
+        tokio::spawn(async move { collector.watch_events(poll_interval).await });
+    }
+    if let Some(url) = args.remote_write_url.clone() {
+        let options = RemoteWriteOptions {
+            url,
+            timeout: Duration::from_secs(args.remote_write_timeout),
+            user: args.remote_write_user.clone(),
+            password: args.remote_write_password.clone(),
+        };
+        let writer = RemoteWriter::new(options)?;
+        let interval = Duration::from_secs(args.remote_write_interval.max(1));
+        tokio::spawn(remote_write(
+            collector.clone(),
+            config.clone(),
+            writer,
+            interval,
+        ));
+    }
+
+    let (address, server) = run_server(args, config, collector)?;
+    info!("Listening on http://{}", address);
+    server.await.map_err(|e| anyhow!("Server: {}", e))
+}
+
+/// Binds the metrics server and returns the address it listens on, which is the ephemeral port
+/// the OS picked for `--port 0`, and the future that serves it.
+fn run_server(
+    args: &AppArgs,
+    config: Arc<ServerConfig>,
+    collector: Arc<Collector>,
+) -> Result<(SocketAddr, impl Future<Output = hyper::Result<()>>)> {
+    // Under systemd socket activation the listening socket is passed in via LISTEN_FDS.
+    let listener = ListenFd::from_env()
+        .take_tcp_listener(0)
+        .map_err(|e| anyhow!("Socket activation: {}", e))?;
+    let server = match listener {
+        Some(listener) => {
+            info!("Using the socket-activated listener");
+            Server::from_tcp(listener).map_err(|e| anyhow!("Socket activation: {}", e))?
+        }
+        None => {
+            let host = match args.listen_address {
+                Some(host) => host,
+                None => {
+                    let addr = IpAddr::from_str(&args.host)
+                        .map_err(|e| anyhow!("Invalid host {}: {}", args.host, e))?;
+                    (addr, args.port).into()
+                }
+            };
+            Server::try_bind(&host).map_err(|e| anyhow!("Bind {}: {}", host, e))?
+        }
+    };
+    // Same defaults as the Go net/http server used by node_exporter.
+    let keepalive = Some(Duration::from_secs(args.http_keepalive)).filter(|d| !d.is_zero());
+    let connections = Some(args.max_connections)
+        .filter(|max| *max > 0)
+        .map(|max| Arc::new(Semaphore::new(max)));
+    let server = server
+        .http1_keepalive(keepalive.is_some())
+        .tcp_keepalive(keepalive)
+        .tcp_nodelay(args.tcp_nodelay)
+        .serve(make_service_fn(move |conn: &AddrStream| {
+            let collector = collector.clone();
+            let config = config.clone();
+            let remote_addr = conn.remote_addr();
+            // The permit is held by the service, so for as long as the connection is open.
+            let permit = connections
+                .as_ref()
+                .map(|connections| connections.clone().try_acquire_owned().ok());
+            let rejected = matches!(permit, Some(None));
+            if rejected {
+                warn!(remote_addr = %remote_addr, "Connection limit reached");
+            }
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req| {
+                    let _permit = &permit;
+                    serve_logged(
+                        req,
+                        remote_addr,
+                        collector.clone(),
+                        config.clone(),
+                        rejected,
+                    )
+                }))
+            }
+        }));
+    Ok((server.local_addr(), server))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cont(name: &str, pod: Option<&str>, state_name: &str) -> ContInfo {
+        ContInfo {
+            pod: pod.map(String::from),
+            name: name.to_string(),
+            state: container_state(Some(state_name)),
+            state_name: state_name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// A collector that isn't connected, for the methods that don't call Podman.
+    fn offline_collector(args: &[&str]) -> Collector {
+        collector("unix:///nonexistent/podman.sock", args)
+    }
+
+    fn collector(uri: &str, args: &[&str]) -> Collector {
+        let args = AppArgs::parse_from(["exporter"].iter().chain(args));
+        let metrics = Arc::new(Metrics::new(&MetricsConfig::from_args(&args)).unwrap());
+        let config = CollectorConfig::from_args(&args);
+        Collector::new(uri, config, metrics).unwrap()
+    }
+
+    fn containers(conts: Vec<(&str, ContInfo)>) -> HashMap<String, ContInfo> {
+        conts
+            .into_iter()
+            .map(|(id, cont)| (id.to_string(), cont))
+            .collect()
+    }
+
+    fn accept_encoding(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(ACCEPT_ENCODING, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn accepts_gzip_when_offered() {
+        assert!(accepts_gzip(&accept_encoding(&["gzip"])));
+        assert!(accepts_gzip(&accept_encoding(&["deflate, GZIP;q=0.5"])));
+        assert!(accepts_gzip(&accept_encoding(&["br", "gzip"])));
+        assert!(accepts_gzip(&accept_encoding(&["*"])));
+    }
+
+    #[test]
+    fn accepts_gzip_when_not_offered() {
+        assert!(!accepts_gzip(&HeaderMap::new()));
+        assert!(!accepts_gzip(&accept_encoding(&["identity"])));
+        assert!(!accepts_gzip(&accept_encoding(&["deflate, br"])));
+        assert!(!accepts_gzip(&accept_encoding(&["gzip;q=0"])));
+        assert!(!accepts_gzip(&accept_encoding(&["*; q=0.0"])));
+    }
+
+    #[test]
+    fn pod_containers_by_state() {
+        let conts = containers(vec![
+            ("a", cont("web-app", Some("web"), "running")),
+            ("b", cont("web-db", Some("web"), "exited")),
+            ("c", cont("web-cache", Some("web"), "stopped")),
+            ("d", cont("web-init", Some("web"), "created")),
+            ("e", cont("solo", None, "exited")),
+        ]);
+        let pods = count_pod_containers_by_state(&conts);
+        assert_eq!(pods.len(), 1);
+        assert_eq!(pods["web"], (1, 2));
+    }
+
+    #[test]
+    fn containers_per_pod_without_pods() {
+        assert!(count_containers_per_pod(&HashMap::new()).is_empty());
+        let conts = containers(vec![
+            ("a", cont("app", None, "running")),
+            ("b", cont("db", None, "running")),
+        ]);
+        assert!(count_containers_per_pod(&conts).is_empty());
+    }
+
+    #[test]
+    fn containers_per_pod_with_several_containers() {
+        let conts = containers(vec![
+            ("a", cont("web-infra", Some("web"), "running")),
+            ("b", cont("web-app", Some("web"), "running")),
+            ("c", cont("web-db", Some("web"), "exited")),
+        ]);
+        let pods = count_containers_per_pod(&conts);
+        assert_eq!(pods.len(), 1);
+        assert_eq!(pods["web"], 3);
+    }
+
+    #[test]
+    fn containers_per_pod_skips_podless_containers() {
+        let conts = containers(vec![
+            ("a", cont("web-app", Some("web"), "running")),
+            ("b", cont("api-app", Some("api"), "running")),
+            ("c", cont("solo", None, "running")),
+        ]);
+        let pods = count_containers_per_pod(&conts);
+        assert_eq!(pods.len(), 2);
+        assert_eq!(pods["web"], 1);
+        assert_eq!(pods["api"], 1);
+        assert_eq!(pods.values().sum::<usize>(), 2);
+    }
+
+    fn config(args: &[&str]) -> CollectorConfig {
+        CollectorConfig::from_args(&AppArgs::parse_from(["exporter"].iter().chain(args)))
+    }
+
+    #[test]
+    fn needs_inspect_for_groups_and_flags() {
+        assert!(config(&[]).needs_inspect());
+        let list_only = [
+            "--collector.enable-only",
+            "container_state",
+            "--collector.enable-only",
+            "pod_info",
+        ];
+        assert!(!config(&list_only).needs_inspect());
+        let started_at = [&list_only[..], &["--collect-started-at"]].concat();
+        assert!(config(&started_at).needs_inspect());
+        let hostname = [&list_only[..], &["--container-name-from", "hostname"]].concat();
+        assert!(config(&hostname).needs_inspect());
+        let starts = [
+            &list_only[..],
+            &["--event-driven", "--start-count-from-inspect"],
+        ]
+        .concat();
+        assert!(config(&starts).needs_inspect());
+        assert!(config(&["--collector.enable-only", "volume_info"]).needs_inspect());
+        assert!(config(&["--collector.enable-only", "secret_info"]).needs_inspect());
+    }
+
+    #[test]
+    fn cpu_count_falls_back_to_host_cpus() {
+        let inspect =
+            |json: &str| -> LibpodContainerInspectResponse { serde_json::from_str(json).unwrap() };
+        let pinned = inspect(r#"{"HostConfig": {"CpusetCpus": "0-3,5"}}"#);
+        assert_eq!(cpu_count(&pinned, Some(16)), Some(5));
+        let unpinned = inspect(r#"{"HostConfig": {"CpusetCpus": ""}}"#);
+        assert_eq!(cpu_count(&unpinned, Some(16)), Some(16));
+        assert_eq!(cpu_count(&unpinned, None), None);
+        assert_eq!(cpu_count(&inspect("{}"), Some(16)), Some(16));
+    }
+
+    #[test]
+    fn info_labels_are_unique() {
+        let labels = |keys: &[&str]| keys.iter().map(|k| k.to_string()).collect::<Vec<_>>();
+        assert!(check_info_labels(&labels(&["app.name", "tier"]), &[]).is_ok());
+        assert!(check_info_labels(&labels(&["app.name", "app-name"]), &[]).is_err());
+        assert!(check_info_labels(&labels(&["image"]), &[]).is_err());
+        assert!(check_info_labels(&[], &labels(&["APP_VERSION", "APP.VERSION"])).is_err());
+        assert!(
+            check_info_labels(&labels(&["env_APP_VERSION"]), &labels(&["APP_VERSION"])).is_err()
+        );
+        assert!(check_info_labels(&labels(&["APP_VERSION"]), &labels(&["APP_VERSION"])).is_ok());
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn container_name_with_multiple_names() {
+        assert_eq!(
+            container_name(&names(&["/web-infra", "/web-app"])).as_deref(),
+            Some("web-app")
+        );
+        assert_eq!(
+            container_name(&names(&["/first", "/second"])).as_deref(),
+            Some("first")
+        );
+        assert_eq!(
+            container_name(&names(&["/", "second"])).as_deref(),
+            Some("second")
+        );
+        // A pod's infra container only has its infra name.
+        assert_eq!(
+            container_name(&names(&["/web-infra"])).as_deref(),
+            Some("web-infra")
+        );
+        assert_eq!(container_name(&names(&[])), None);
+        assert_eq!(container_name(&names(&["/"])), None);
+    }
+
+    #[test]
+    fn uptime_nanos_without_up_time() {
+        let mut running = cont("app", None, "running");
+        running.started_at = Some(1_000);
+        assert_eq!(uptime_nanos(None, &running, 1_060), 60_000_000_000);
+        // A clock skew doesn't give a negative uptime.
+        assert_eq!(uptime_nanos(None, &running, 900), 0);
+        assert_eq!(uptime_nanos(Some(5), &running, 1_060), 5);
+
+        let mut exited = cont("app", None, "exited");
+        exited.started_at = Some(1_000);
+        assert_eq!(uptime_nanos(None, &exited, 1_060), 0);
+
+        let never_started = cont("app", None, "running");
+        assert_eq!(uptime_nanos(None, &never_started, 1_060), 0);
+    }
+
+    #[test]
+    fn name_allowed_filters() {
+        let include = Regex::new("^web-").unwrap();
+        let exclude = Regex::new("-debug$").unwrap();
+        assert!(name_allowed("anything", None, None));
+        assert!(name_allowed("web-app", Some(&include), None));
+        assert!(!name_allowed("db", Some(&include), None));
+        assert!(!name_allowed("db-debug", None, Some(&exclude)));
+        assert!(name_allowed("db", None, Some(&exclude)));
+        // The exclude wins over the include.
+        assert!(!name_allowed("web-debug", Some(&include), Some(&exclude)));
+        assert!(name_allowed("web-app", Some(&include), Some(&exclude)));
+        assert!(!name_allowed("db", Some(&include), Some(&exclude)));
+    }
+
+    /// Writes the first family, then fails like an encoder hitting a bad family.
+    struct FailingEncoder;
+
+    impl Encoder for FailingEncoder {
+        fn encode<W: Write>(
+            &self,
+            families: &[MetricFamily],
+            writer: &mut W,
+        ) -> prometheus::Result<()> {
+            if let Some(family) = families.first() {
+                writeln!(writer, "{} 1", family.get_name())?;
+            }
+            Err(prometheus::Error::Msg("bad family".to_string()))
+        }
+
+        fn format_type(&self) -> &str {
+            TEXT_FORMAT
+        }
+    }
+
+    fn families(names: &[&str]) -> Vec<MetricFamily> {
+        names
+            .iter()
+            .map(|name| {
+                let mut family = MetricFamily::default();
+                family.set_name(name.to_string());
+                family
+            })
+            .collect()
+    }
+
+    #[test]
+    fn encode_with_failing_encoder() {
+        let errors = Counter::new("encode_errors", "errors").unwrap();
+        let buffer = encode(&FailingEncoder, &families(&["first", "second"]), &errors);
+        assert_eq!(buffer, b"first 1\n");
+        assert_eq!(errors.get(), 1.0);
+
+        let buffer = encode(&FailingEncoder, &[], &errors);
+        assert!(buffer.is_empty());
+        assert_eq!(errors.get(), 2.0);
+
+        let buffer = encode(&TextEncoder::new(), &[], &errors);
+        assert!(buffer.is_empty());
+        assert_eq!(errors.get(), 2.0);
+    }
+
+    #[test]
+    fn interface_packets_need_a_unix_uri() {
+        let args = AppArgs::parse_from(["exporter", "--collect-interface-packets"]);
+        let metrics = Arc::new(Metrics::new(&MetricsConfig::from_args(&args)).unwrap());
+        let new = |uri| Collector::new(uri, CollectorConfig::from_args(&args), metrics.clone());
+        assert!(new("tcp://127.0.0.1:8080").is_err());
+        assert!(new("unix:///run/podman/podman.sock").is_ok());
+    }
+
+    #[test]
+    fn fds_need_a_unix_uri() {
+        let args = AppArgs::parse_from(["exporter", "--collect-fds"]);
+        let metrics = Arc::new(Metrics::new(&MetricsConfig::from_args(&args)).unwrap());
+        let new = |uri| Collector::new(uri, CollectorConfig::from_args(&args), metrics.clone());
+        assert!(new("ssh://core@10.0.0.2/run/podman/podman.sock").is_err());
+        assert!(new("unix:///run/podman/podman.sock").is_ok());
+    }
+
+    fn list_container(json: &str) -> ListContainer {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn container_state_values() {
+        assert_eq!(container_state(Some("exited")), 0);
+        assert_eq!(container_state(Some("stopped")), 0);
+        assert_eq!(container_state(Some("running")), 1);
+        assert_eq!(container_state(Some("created")), 2);
+        assert_eq!(container_state(Some("paused")), -1);
+        assert_eq!(container_state(Some("")), -1);
+        assert_eq!(container_state(None), -1);
+    }
+
+    #[test]
+    fn pod_name_of_listed_container() {
+        let container = list_container(r#"{"Id": "a", "Pod": "1234", "PodName": "web"}"#);
+        assert_eq!(pod_name(&container).as_deref(), Some("web"));
+        let container = list_container(r#"{"Id": "b", "Pod": "", "PodName": ""}"#);
+        assert_eq!(pod_name(&container), None);
+        let container = list_container(r#"{"Id": "c"}"#);
+        assert_eq!(pod_name(&container), None);
+    }
+
+    #[test]
+    fn container_list_opts_query() {
+        let config = CollectorConfig::from_args(&AppArgs::parse_from(["exporter"]));
+        let query = container_list_opts(&config).serialize().unwrap();
+        assert!(query.contains("all=true"), "{}", query);
+        assert!(!query.contains("size=true"), "{}", query);
+
+        let args = AppArgs::parse_from(["exporter", "--running-only", "--collect-sizes"]);
+        let query = container_list_opts(&CollectorConfig::from_args(&args))
+            .serialize()
+            .unwrap();
+        assert!(!query.contains("all=true"), "{}", query);
+        assert!(query.contains("size=true"), "{}", query);
+    }
+
+    #[test]
+    fn list_entry_with_sizes() {
+        let container = list_container(
+            r#"{
+                "Id": "abc",
+                "Names": ["app"],
+                "Image": "docker.io/library/alpine:latest",
+                "State": "running",
+                "Size": {"rwSize": 4096, "rootFsSize": 7340032}
+            }"#,
+        );
+        let cont = offline_collector(&["--collect-sizes"]).cont_info(
+            "app".to_string(),
+            container,
+            None,
+            None,
+        );
+        assert_eq!(cont.rw_size, Some(4096));
+        assert_eq!(cont.rootfs_size, Some(7340032));
+
+        let container = list_container(r#"{"Id": "abc", "Names": ["app"], "State": "running"}"#);
+        let cont = offline_collector(&[]).cont_info("app".to_string(), container, None, None);
+        assert_eq!(cont.rw_size, None);
+        assert_eq!(cont.rootfs_size, None);
+    }
+
+    #[test]
+    fn max_scrape_timeout_above_zero() {
+        let parse = |value| AppArgs::try_parse_from(["exporter", "--max-scrape-timeout", value]);
+        assert_eq!(parse("2.5").unwrap().max_scrape_timeout, Some(2.5));
+        for value in ["0", "-1", "NaN", "inf", "soon"] {
+            assert!(parse(value).is_err(), "{}", value);
+        }
+    }
+
+    /// Collections against a mock of the Podman API.
+    mod integration_tests {
+        use super::*;
+        use hyper::server::conn::AddrStream;
+        use prometheus::proto::Metric;
+
+        /// A Podman API answering with canned JSON by path without the version prefix, e.g.
+        /// `/containers/json` for `/v4.0.0/libpod/containers/json?all=true`. Other paths get a
+        /// `404`. Every answer is held back by `delay`. Returns the `tcp://` URI to connect to.
+        fn mock_podman(routes: &[(&str, &str)], delay: Duration) -> String {
+            let routes: Arc<HashMap<String, String>> = Arc::new(
+                routes
+                    .iter()
+                    .map(|(path, body)| (path.to_string(), body.to_string()))
+                    .collect(),
+            );
+            let make_service = make_service_fn(move |_: &AddrStream| {
+                let routes = routes.clone();
+                async move {
+                    Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                        let routes = routes.clone();
+                        async move {
+                            time::sleep(delay).await;
+                            let path = req.uri().path();
+                            let path = path.find("/libpod").map_or(path, |i| &path[i + 7..]);
+                            Ok::<_, hyper::Error>(mock_response(path, routes.get(path)))
+                        }
+                    }))
+                }
+            });
+            let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+            let uri = format!("tcp://{}", server.local_addr());
+            tokio::spawn(server);
+            uri
+        }
+
+        fn mock_response(path: &str, body: Option<&String>) -> Response<Body> {
+            let body = match body {
+                Some(body) => body.clone(),
+                None => {
+                    let body =
+                        r#"{"cause": "no such path", "message": "no such path", "response": 404}"#;
+                    return response(StatusCode::NOT_FOUND, "application/json", body.into());
+                }
+            };
+            let mut response = response(StatusCode::OK, "application/json", body.into_bytes());
+            if path == "/_ping" {
+                let headers = response.headers_mut();
+                for (name, value) in [
+                    ("api-version", "1.41"),
+                    ("libpod-api-version", "4.9.3"),
+                    ("libpod-buildah-version", "1.33.7"),
+                    ("docker-experimental", "true"),
+                    ("cache-control", "no-cache"),
+                    ("pragma", "no-cache"),
+                ] {
+                    headers.insert(name, HeaderValue::from_static(value));
+                }
+            }
+            response
+        }
+
+        /// The value of the first `name` series with the `label` name and value.
+        fn series_value(families: &[MetricFamily], name: &str, label: (&str, &str)) -> Option<f64> {
+            let family = families.iter().find(|f| f.get_name() == name)?;
+            let metric = family.get_metric().iter().find(|m| {
+                m.get_label()
+                    .iter()
+                    .any(|l| (l.get_name(), l.get_value()) == label)
+            })?;
+            Some(sample(metric))
+        }
+
+        fn sample(metric: &Metric) -> f64 {
+            if metric.has_counter() {
+                metric.get_counter().get_value()
+            } else {
+                metric.get_gauge().get_value()
+            }
+        }
+
+        /// The value of a metric without labels.
+        fn value(families: &[MetricFamily], name: &str) -> Option<f64> {
+            let family = families.iter().find(|f| f.get_name() == name)?;
+            family.get_metric().first().map(sample)
+        }
+
+        const MOCK_CONTAINERS: &str = r#"[
+            {
+                "Id": "aaa",
+                "Names": ["web-app"],
+                "Image": "docker.io/library/nginx:latest",
+                "ImageID": "sha256:1111",
+                "State": "running",
+                "Pod": "p1",
+                "PodName": "web",
+                "StartedAt": 1700000000
+            },
+            {
+                "Id": "bbb",
+                "Names": ["batch"],
+                "Image": "docker.io/library/alpine:latest",
+                "ImageID": "sha256:2222",
+                "State": "exited",
+                "StartedAt": 1700000000
+            }
+        ]"#;
+
+        const MOCK_STATS: &str = r#"{
+            "Error": null,
+            "Stats": [
+                {
+                    "ContainerID": "aaa",
+                    "Name": "web-app",
+                    "CPU": 12.5,
+                    "AvgCPU": 10.0,
+                    "CPUNano": 3000000000,
+                    "CPUSystemNano": 1000000000,
+                    "SystemNano": 1700000000000000000,
+                    "MemUsage": 1048576,
+                    "MemLimit": 2097152,
+                    "MemPerc": 50.0,
+                    "NetInput": 100,
+                    "NetOutput": 200,
+                    "BlockInput": 300,
+                    "BlockOutput": 400,
+                    "PIDs": 3,
+                    "UpTime": 5000000000,
+                    "PerCPU": [1000000000, 2000000000]
+                }
+            ]
+        }"#;
+
+        #[tokio::test]
+        async fn update_stat_from_mock_podman() {
+            let uri = mock_podman(
+                &[
+                    ("/_ping", "OK"),
+                    ("/containers/json", MOCK_CONTAINERS),
+                    ("/containers/stats", MOCK_STATS),
+                ],
+                Duration::ZERO,
+            );
+            let collector = collector(&uri, &[]);
+            collector.negotiate_api_version().await.unwrap();
+            collector.update_stat().await.unwrap();
+
+            let config = ServerConfig::from_args(&AppArgs::parse_from(["exporter"]));
+            let families = gather(&collector.metrics, &config);
+            assert_eq!(value(&families, "podman_container_total"), Some(2.0));
+            let misses = "podman_exporter_stat_correlation_misses_total";
+            assert_eq!(value(&families, misses), Some(0.0));
+            let value = |name, container| series_value(&families, name, ("container", container));
+            assert_eq!(value("podman_container_state", "web-app"), Some(1.0));
+            assert_eq!(value("podman_container_running", "batch"), Some(0.0));
+            assert_eq!(value("podman_container_cpu", "web-app"), Some(12.5));
+            assert_eq!(
+                value("podman_container_mem_usage", "web-app"),
+                Some(1048576.0)
+            );
+            assert_eq!(
+                value("podman_container_mem_limit", "web-app"),
+                Some(2097152.0)
+            );
+            assert_eq!(value("podman_container_pids", "web-app"), Some(3.0));
+            assert_eq!(value("podman_container_uptime", "web-app"), Some(5e9));
+            assert_eq!(value("podman_container_mem_usage", "batch"), None);
+            let pod_count = series_value(&families, "podman_container_count", ("pod", "web"));
+            assert_eq!(pod_count, Some(1.0));
+        }
+
+        #[tokio::test]
+        async fn slow_collection_respects_the_scrape_timeout() {
+            let uri = mock_podman(
+                &[
+                    ("/containers/json", MOCK_CONTAINERS),
+                    ("/containers/stats", MOCK_STATS),
+                ],
+                Duration::from_secs(5),
+            );
+            let req = Request::get("/metrics")
+                .header("X-Prometheus-Scrape-Timeout-Seconds", "1.5")
+                .body(Body::empty())
+                .unwrap();
+            let started = Instant::now();
+            let config = ServerConfig::from_args(&AppArgs::parse_from(["exporter"]));
+            let resp = serve_req(req, Arc::new(collector(&uri, &[])), &config)
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+            let elapsed = started.elapsed();
+            assert!(elapsed >= Duration::from_secs(1), "{:?}", elapsed);
+            assert!(elapsed < Duration::from_secs(4), "{:?}", elapsed);
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            let body = String::from_utf8_lossy(&body);
+            assert!(body.contains("timed out"), "{}", body);
+        }
+
+        #[tokio::test]
+        async fn ignored_pod_is_not_collected() {
+            let containers = r#"[
+                {"Id": "aaa", "Names": ["web-app"], "State": "running", "PodName": "web"},
+                {"Id": "sss", "Names": ["system-agent"], "State": "running", "PodName": "system"}
+            ]"#;
+            let stats = r#"{
+                "Error": null,
+                "Stats": [
+                    {"ContainerID": "aaa", "Name": "web-app", "MemUsage": 1024},
+                    {"ContainerID": "sss", "Name": "system-agent", "MemUsage": 2048}
+                ]
+            }"#;
+            let uri = mock_podman(
+                &[
+                    ("/containers/json", containers),
+                    ("/containers/stats", stats),
+                ],
+                Duration::ZERO,
+            );
+            let collector = collector(&uri, &["--ignore-pod", "system"]);
+            collector.update_stat().await.unwrap();
+
+            let config = ServerConfig::from_args(&AppArgs::parse_from(["exporter"]));
+            let families = gather(&collector.metrics, &config);
+            assert_eq!(value(&families, "podman_container_total"), Some(1.0));
+            let misses = "podman_exporter_stat_correlation_misses_total";
+            assert_eq!(value(&families, misses), Some(0.0));
+            let web_count = series_value(&families, "podman_container_count", ("pod", "web"));
+            assert_eq!(web_count, Some(1.0));
+            let system_series = families
+                .iter()
+                .flat_map(|f| f.get_metric())
+                .filter(|m| {
+                    m.get_label()
+                        .iter()
+                        .any(|l| l.get_value() == "system" || l.get_value() == "system-agent")
+                })
+                .count();
+            assert_eq!(system_series, 0);
+        }
+    }
+
+    /// Serves `collector` on an ephemeral port with the server settings of `args`.
+    fn serve(args: &[&str], collector: Arc<Collector>) -> SocketAddr {
+        let args = AppArgs::parse_from(
+            ["exporter", "--host", "127.0.0.1", "--port", "0"]
+                .iter()
+                .chain(args),
+        );
+        let config = Arc::new(ServerConfig::from_args(&args));
+        let (address, server) = run_server(&args, config, collector).unwrap();
+        tokio::spawn(server);
+        address
+    }
+
+    #[tokio::test]
+    async fn server_uses_the_given_args() {
+        let args = [
+            "--event-driven",
+            "--web.metrics-path",
+            "/custom",
+            "--metric-naming",
+            "strict",
+            "--web.disable-exporter-metrics",
+        ];
+        let address = serve(&args, Arc::new(offline_collector(&[])));
+        let not_found = reqwest::get(format!("http://{}/metrics", address))
+            .await
+            .unwrap();
+        assert_eq!(not_found.status(), 404);
+        let resp = reqwest::get(format!("http://{}/custom", address))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        let body = resp.text().await.unwrap();
+        assert!(body.contains("podman_containers "), "{}", body);
+        assert!(!body.contains("podman_container_total"), "{}", body);
+        assert!(!body.contains("podman_exporter_scrapes_total"), "{}", body);
+    }
+
+    #[test]
+    fn collects_container_filters() {
+        let collector = offline_collector(&[
+            "--name-exclude=-debug$",
+            "--exclude-infra",
+            "--ignore-pod",
+            "system",
+        ]);
+        let app = list_container(r#"{"Id": "a", "PodName": "web"}"#);
+        assert!(collector.collects_container("web-app", &app));
+        assert!(!collector.collects_container("web-debug", &app));
+        let infra = list_container(r#"{"Id": "b", "PodName": "web", "IsInfra": true}"#);
+        assert!(!collector.collects_container("web-infra", &infra));
+        let ignored = list_container(r#"{"Id": "c", "PodName": "system"}"#);
+        assert!(!collector.collects_container("system-app", &ignored));
+        let podless = list_container(r#"{"Id": "d", "PodName": ""}"#);
+        assert!(collector.collects_container("solo", &podless));
     }
 }
@@ -0,0 +1,742 @@
+use crate::bounded::{BoundedCounterVec, BoundedGaugeVec, ContainerVec};
+use prometheus::{Counter, CounterVec, Gauge, GaugeVec, Opts, Registry, Result};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Settings that change the label sets of the per-container metrics.
+#[derive(Debug, Clone, Default)]
+pub struct MetricsConfig {
+    pub expose_labels: Vec<String>,
+    pub expose_env: Vec<String>,
+    pub max_label_combinations: Option<usize>,
+    pub no_pod_label: bool,
+    pub process_metrics: bool,
+}
+
+/// The exported metrics, registered in registries owned by this instance. The `podman_*`
+/// metrics collected from Podman are in `registry`, the metrics about the exporter itself in
+/// `exporter_registry` so they can be left out with `--web.disable-exporter-metrics`.
+pub struct Metrics {
+    pub registry: Registry,
+    pub exporter_registry: Registry,
+    /// Labels added to `podman_container_info` when it's gathered, by container ID. Their names
+    /// differ between containers, which a metric vec can't express.
+    pub dynamic_info_labels: Mutex<HashMap<String, Vec<(String, String)>>>,
+    pub build_info: GaugeVec,
+    pub stat_correlation_misses: Counter,
+    pub last_collection_timestamp: Gauge,
+    pub scrapes: Counter,
+    pub scrape_errors: Counter,
+    pub encode_errors: Counter,
+    pub container_total: Gauge,
+    pub container_name_collisions: Gauge,
+    pub containers_mem_usage_total: Gauge,
+    pub containers_cpu_total: Gauge,
+    pub container_count: BoundedGaugeVec,
+    pub container_info: BoundedGaugeVec,
+    pub container_state: BoundedGaugeVec,
+    pub container_running: BoundedGaugeVec,
+    pub container_uptime: BoundedGaugeVec,
+    pub container_started_at: BoundedGaugeVec,
+    pub container_start_count: BoundedCounterVec,
+    pub container_uptime_calc: BoundedGaugeVec,
+    pub container_system_nano: BoundedGaugeVec,
+    pub container_pids: BoundedGaugeVec,
+    pub container_open_fds: BoundedGaugeVec,
+    pub container_avg_cpu: BoundedGaugeVec,
+    pub container_cpu: BoundedGaugeVec,
+    pub container_cpu_count: BoundedGaugeVec,
+    pub container_cpu_nano: BoundedGaugeVec,
+    pub container_cpu_system_nano: BoundedGaugeVec,
+    pub container_cpu_user_nano: BoundedGaugeVec,
+    pub container_cpu_kernel_nano: BoundedGaugeVec,
+    pub container_cpu_per_core: BoundedGaugeVec,
+    pub container_cpu_throttled_periods: BoundedCounterVec,
+    pub container_cpu_throttled_seconds: BoundedCounterVec,
+    pub container_mem_usage: BoundedGaugeVec,
+    pub container_mem_limit: BoundedGaugeVec,
+    pub container_mem_unlimited: BoundedGaugeVec,
+    pub container_mem_perc: BoundedGaugeVec,
+    pub container_net_inp: BoundedGaugeVec,
+    pub container_net_out: BoundedGaugeVec,
+    pub container_net_total_inp: BoundedGaugeVec,
+    pub container_net_total_out: BoundedGaugeVec,
+    pub container_net_if_rx_packets: BoundedGaugeVec,
+    pub container_net_if_tx_packets: BoundedGaugeVec,
+    pub container_bl_inp: BoundedGaugeVec,
+    pub container_bl_out: BoundedGaugeVec,
+    pub container_io_read: BoundedCounterVec,
+    pub container_io_write: BoundedCounterVec,
+    pub container_blkio_read_ops: BoundedCounterVec,
+    pub container_blkio_write_ops: BoundedCounterVec,
+    pub container_mounts: BoundedGaugeVec,
+    pub container_network_info: BoundedGaugeVec,
+    pub pod_running_containers: BoundedGaugeVec,
+    pub pod_stopped_containers: BoundedGaugeVec,
+    pub pod_cpu: BoundedGaugeVec,
+    pub pod_mem_usage: BoundedGaugeVec,
+    pub pod_net_input: BoundedGaugeVec,
+    pub pod_net_output: BoundedGaugeVec,
+    pub systemd_unit_active_state: BoundedGaugeVec,
+    pub container_volumes_mounted: BoundedGaugeVec,
+    pub container_volume_mount_info: BoundedGaugeVec,
+    pub container_secret_count: BoundedGaugeVec,
+    pub container_secret_info: BoundedGaugeVec,
+    pub container_rw_size: BoundedGaugeVec,
+    pub container_rootfs_size: BoundedGaugeVec,
+    pub container_oom_score_adj: BoundedGaugeVec,
+    pub container_ulimit_soft: BoundedGaugeVec,
+    pub container_ulimit_hard: BoundedGaugeVec,
+    pub container_restart_policy_info: BoundedGaugeVec,
+    pub container_privileged: BoundedGaugeVec,
+    pub container_readonly_rootfs: BoundedGaugeVec,
+    pub container_run_as_root: BoundedGaugeVec,
+    pub container_device_access_info: BoundedGaugeVec,
+    pub container_capability_info: BoundedGaugeVec,
+    pub pod_by_state: GaugeVec,
+    pub secret_total: Gauge,
+    pub system_images_size: Gauge,
+    pub system_containers_size: Gauge,
+    pub system_volumes_size: Gauge,
+    pub system_total_size: Gauge,
+    /// Handles of the vecs labeled by `pod` and `container`.
+    container_vecs: Vec<ContainerVec>,
+}
+
+impl Metrics {
+    pub fn new(config: &MetricsConfig) -> Result<Self> {
+        let registry = Registry::new();
+        let exporter_registry = Registry::new();
+        let label_limit_exceeded = Counter::new(
+            "podman_exporter_label_limit_exceeded_total",
+            "Count of label combinations skipped because of --max-label-combinations",
+        )?;
+        exporter_registry.register(Box::new(label_limit_exceeded.clone()))?;
+        // `process_cpu_seconds_total`, `process_resident_memory_bytes`, ... read from /proc.
+        #[cfg(target_os = "linux")]
+        if config.process_metrics {
+            let process = prometheus::process_collector::ProcessCollector::for_self();
+            exporter_registry.register(Box::new(process))?;
+        }
+        let exporter = Builder {
+            registry: exporter_registry.clone(),
+            config,
+            label_limit_exceeded: label_limit_exceeded.clone(),
+            container_vecs: RefCell::new(Vec::new()),
+        };
+        let podman = Builder {
+            registry: registry.clone(),
+            config,
+            label_limit_exceeded,
+            container_vecs: RefCell::new(Vec::new()),
+        };
+        Ok(Self {
+            registry,
+            exporter_registry,
+            dynamic_info_labels: Mutex::new(HashMap::new()),
+            build_info: exporter.gauge_vec(
+                "podman_exporter_build_info",
+                "Exporter build information (always 1)",
+                &["version", "build_date", "git_commit", "rust_version"],
+            )?,
+            stat_correlation_misses: exporter.counter(
+                "podman_exporter_stat_correlation_misses_total",
+                "Count of container stats without a matching container in the container list",
+            )?,
+            last_collection_timestamp: exporter.gauge(
+                "podman_exporter_last_collection_timestamp_seconds",
+                "Unix timestamp of the last successful collection",
+            )?,
+            scrapes: exporter.counter(
+                "podman_exporter_scrapes_total",
+                "Count of requests to the metrics path",
+            )?,
+            scrape_errors: exporter.counter(
+                "podman_exporter_scrape_errors_total",
+                "Count of collections that failed after all retries",
+            )?,
+            encode_errors: exporter.counter(
+                "podman_exporter_encode_errors_total",
+                "Count of scrapes in which encoding the metrics failed",
+            )?,
+            container_total: podman.gauge("podman_container_total", "Total count of containers")?,
+            container_name_collisions: podman.gauge(
+                "podman_container_name_collisions_total",
+                "Count of container names used by more than one container",
+            )?,
+            containers_mem_usage_total: podman.gauge(
+                "podman_containers_mem_usage_bytes_total",
+                "Memory usage summed over all containers (bytes)",
+            )?,
+            containers_cpu_total: podman.gauge(
+                "podman_containers_cpu_total",
+                "CPU usage summed over all containers",
+            )?,
+            container_count: podman.bounded_gauge_vec(
+                "podman_container_count",
+                "Count of containers",
+                &["pod"],
+            )?,
+            container_info: podman.bounded_gauge_vec(
+                "podman_container_info",
+                "Container info",
+                &container_info_labels(&config.expose_labels, &config.expose_env)
+                    .iter()
+                    .map(String::as_str)
+                    .collect::<Vec<_>>(),
+            )?,
+            container_state: podman.bounded_gauge_vec(
+                "podman_container_state",
+                "Container current state (-1=unknown,0=exited/stopped,1=running,2=created)",
+                &["pod", "container"],
+            )?,
+            container_running: podman.bounded_gauge_vec(
+                "podman_container_running",
+                "Container is running (0/1)",
+                &["pod", "container"],
+            )?,
+            container_uptime: podman.bounded_gauge_vec(
+                "podman_container_uptime",
+                "Container uptime",
+                &["pod", "container"],
+            )?,
+            container_started_at: podman.bounded_gauge_vec(
+                "podman_container_started_at_seconds",
+                "Unix timestamp of the last container start",
+                &["pod", "container"],
+            )?,
+            container_start_count: podman.bounded_counter_vec(
+                "podman_container_start_count_total",
+                "Count of container starts seen in the Podman events",
+                &["pod", "container"],
+            )?,
+            container_uptime_calc: podman.bounded_gauge_vec(
+                "podman_container_uptime_calc",
+                "Container uptime (Calculated value)",
+                &["pod", "container"],
+            )?,
+            container_system_nano: podman.bounded_gauge_vec(
+                "podman_container_system_nano",
+                "Container system nano",
+                &["pod", "container"],
+            )?,
+            container_pids: podman.bounded_gauge_vec(
+                "podman_container_pids",
+                "Count of running pids in container",
+                &["pod", "container"],
+            )?,
+            container_open_fds: podman.bounded_gauge_vec(
+                "podman_container_open_fds",
+                "Count of open file descriptors of the container's main process",
+                &["pod", "container"],
+            )?,
+            container_avg_cpu: podman.bounded_gauge_vec(
+                "podman_container_avg_cpu",
+                "Container Avg CPU usage",
+                &["pod", "container"],
+            )?,
+            container_cpu: podman.bounded_gauge_vec(
+                "podman_container_cpu",
+                "Container CPU usage",
+                &["pod", "container"],
+            )?,
+            container_cpu_count: podman.bounded_gauge_vec(
+                "podman_container_cpu_count",
+                "Count of CPUs available to container",
+                &["pod", "container"],
+            )?,
+            container_cpu_nano: podman.bounded_gauge_vec(
+                "podman_container_cpu_nano",
+                "Container CPU usage (nano)",
+                &["pod", "container"],
+            )?,
+            container_cpu_system_nano: podman.bounded_gauge_vec(
+                "podman_container_cpu_system_nano",
+                "Container CPU usage (system nano)",
+                &["pod", "container"],
+            )?,
+            container_cpu_user_nano: podman.bounded_gauge_vec(
+                "podman_container_cpu_user_nanoseconds",
+                "Container CPU time in user mode (nanoseconds)",
+                &["pod", "container"],
+            )?,
+            container_cpu_kernel_nano: podman.bounded_gauge_vec(
+                "podman_container_cpu_kernel_nanoseconds",
+                "Container CPU time in kernel mode (nanoseconds)",
+                &["pod", "container"],
+            )?,
+            container_cpu_per_core: podman.bounded_gauge_vec(
+                "podman_container_cpu_per_core_nanoseconds",
+                "Container CPU time per core (nanoseconds)",
+                &["pod", "container", "cpu_core"],
+            )?,
+            container_cpu_throttled_periods: podman.bounded_counter_vec(
+                "podman_container_cpu_throttled_periods_total",
+                "Count of CPU periods in which container was throttled",
+                &["pod", "container"],
+            )?,
+            container_cpu_throttled_seconds: podman.bounded_counter_vec(
+                "podman_container_cpu_throttled_seconds_total",
+                "Total time container was throttled (seconds)",
+                &["pod", "container"],
+            )?,
+            container_mem_usage: podman.bounded_gauge_vec(
+                "podman_container_mem_usage",
+                "Container memory usage (bytes)",
+                &["pod", "container"],
+            )?,
+            container_mem_limit: podman.bounded_gauge_vec(
+                "podman_container_mem_limit",
+                "Container memory limit",
+                &["pod", "container"],
+            )?,
+            container_mem_unlimited: podman.bounded_gauge_vec(
+                "podman_container_mem_unlimited",
+                "Container has no memory limit (0/1)",
+                &["pod", "container"],
+            )?,
+            container_mem_perc: podman.bounded_gauge_vec(
+                "podman_container_mem_perc",
+                "Container memory usage (percentage)",
+                &["pod", "container"],
+            )?,
+            container_net_inp: podman.bounded_gauge_vec(
+                "podman_container_network_input",
+                "Container network input (deprecated, use podman_container_network_total_input_bytes)",
+                &["pod", "container"],
+            )?,
+            container_net_out: podman.bounded_gauge_vec(
+                "podman_container_network_output",
+                "Container network output (deprecated, use podman_container_network_total_output_bytes)",
+                &["pod", "container"],
+            )?,
+            container_net_total_inp: podman.bounded_gauge_vec(
+                "podman_container_network_total_input_bytes",
+                "Container network input summed over all interfaces (bytes)",
+                &["pod", "container"],
+            )?,
+            container_net_total_out: podman.bounded_gauge_vec(
+                "podman_container_network_total_output_bytes",
+                "Container network output summed over all interfaces (bytes)",
+                &["pod", "container"],
+            )?,
+            container_net_if_rx_packets: podman.bounded_gauge_vec(
+                "podman_container_network_interface_rx_packets",
+                "Packets received on a container network interface",
+                &["pod", "container", "interface"],
+            )?,
+            container_net_if_tx_packets: podman.bounded_gauge_vec(
+                "podman_container_network_interface_tx_packets",
+                "Packets sent from a container network interface",
+                &["pod", "container", "interface"],
+            )?,
+            container_bl_inp: podman.bounded_gauge_vec(
+                "podman_container_block_input",
+                "Container block input (deprecated, use podman_container_io_read_bytes_total)",
+                &["pod", "container"],
+            )?,
+            container_bl_out: podman.bounded_gauge_vec(
+                "podman_container_block_output",
+                "Container block output (deprecated, use podman_container_io_write_bytes_total)",
+                &["pod", "container"],
+            )?,
+            container_io_read: podman.bounded_counter_vec(
+                "podman_container_io_read_bytes_total",
+                "Total bytes read by container from block devices",
+                &["pod", "container"],
+            )?,
+            container_io_write: podman.bounded_counter_vec(
+                "podman_container_io_write_bytes_total",
+                "Total bytes written by container to block devices",
+                &["pod", "container"],
+            )?,
+            container_blkio_read_ops: podman.bounded_counter_vec(
+                "podman_container_blkio_read_ops_total",
+                "Total read operations of container on block devices",
+                &["pod", "container"],
+            )?,
+            container_blkio_write_ops: podman.bounded_counter_vec(
+                "podman_container_blkio_write_ops_total",
+                "Total write operations of container on block devices",
+                &["pod", "container"],
+            )?,
+            container_mounts: podman.bounded_gauge_vec(
+                "podman_container_mounts",
+                "Count of container mounts",
+                &["pod", "container"],
+            )?,
+            container_network_info: podman.bounded_gauge_vec(
+                "podman_container_network_info",
+                "Container network info",
+                &["pod", "container", "network"],
+            )?,
+            pod_running_containers: podman.bounded_gauge_vec(
+                "podman_pod_running_containers",
+                "Count of running containers in the pod",
+                &["pod"],
+            )?,
+            pod_stopped_containers: podman.bounded_gauge_vec(
+                "podman_pod_stopped_containers",
+                "Count of exited or stopped containers in the pod",
+                &["pod"],
+            )?,
+            pod_cpu: podman.bounded_gauge_vec(
+                "podman_pod_cpu",
+                "CPU usage summed over the containers of the pod",
+                &["pod"],
+            )?,
+            pod_mem_usage: podman.bounded_gauge_vec(
+                "podman_pod_mem_usage",
+                "Memory usage summed over the containers of the pod (bytes)",
+                &["pod"],
+            )?,
+            pod_net_input: podman.bounded_gauge_vec(
+                "podman_pod_net_input_bytes",
+                "Network input of the pod (bytes)",
+                &["pod"],
+            )?,
+            pod_net_output: podman.bounded_gauge_vec(
+                "podman_pod_net_output_bytes",
+                "Network output of the pod (bytes)",
+                &["pod"],
+            )?,
+            systemd_unit_active_state: podman.bounded_gauge_vec(
+                "podman_systemd_unit_active_state",
+                "State of the systemd unit of the container (1 = active, 0 = inactive, -1 = failed)",
+                &["pod", "container", "unit_name"],
+            )?,
+            container_volumes_mounted: podman.bounded_gauge_vec(
+                "podman_container_volumes_mounted",
+                "Count of volumes mounted in container",
+                &["pod", "container"],
+            )?,
+            container_volume_mount_info: podman.bounded_gauge_vec(
+                "podman_container_volume_mount_info",
+                "Container volume mount info",
+                &["pod", "container", "volume_name", "mount_path", "read_only"],
+            )?,
+            container_secret_count: podman.bounded_gauge_vec(
+                "podman_container_secret_count",
+                "Count of secrets mounted in container",
+                &["pod", "container"],
+            )?,
+            container_secret_info: podman.bounded_gauge_vec(
+                "podman_container_secret_info",
+                "Container secret info",
+                &["pod", "container", "secret_name", "secret_id"],
+            )?,
+            container_rw_size: podman.bounded_gauge_vec(
+                "podman_container_rw_size_bytes",
+                "Size of container writable layer (bytes)",
+                &["pod", "container"],
+            )?,
+            container_rootfs_size: podman.bounded_gauge_vec(
+                "podman_container_rootfs_size_bytes",
+                "Size of container root filesystem, image layers included (bytes)",
+                &["pod", "container"],
+            )?,
+            container_oom_score_adj: podman.bounded_gauge_vec(
+                "podman_container_oom_score_adj",
+                "OOM score adjustment of the container processes",
+                &["pod", "container"],
+            )?,
+            container_ulimit_soft: podman.bounded_gauge_vec(
+                "podman_container_ulimit_soft",
+                "Soft limit of a container ulimit (-1 = unlimited)",
+                &["pod", "container", "type"],
+            )?,
+            container_ulimit_hard: podman.bounded_gauge_vec(
+                "podman_container_ulimit_hard",
+                "Hard limit of a container ulimit (-1 = unlimited)",
+                &["pod", "container", "type"],
+            )?,
+            container_restart_policy_info: podman.bounded_gauge_vec(
+                "podman_container_restart_policy_info",
+                "Container restart policy",
+                &["pod", "container", "policy", "max_retry_count"],
+            )?,
+            container_privileged: podman.bounded_gauge_vec(
+                "podman_container_privileged",
+                "Container runs in privileged mode (0/1)",
+                &["pod", "container"],
+            )?,
+            container_readonly_rootfs: podman.bounded_gauge_vec(
+                "podman_container_readonly_rootfs",
+                "Container root filesystem is read-only (0/1)",
+                &["pod", "container"],
+            )?,
+            container_run_as_root: podman.bounded_gauge_vec(
+                "podman_container_run_as_root",
+                "Container runs as UID 0 (0/1)",
+                &["pod", "container"],
+            )?,
+            container_device_access_info: podman.bounded_gauge_vec(
+                "podman_container_device_access_info",
+                "Host device the container has access to",
+                &["pod", "container", "device_path", "permissions"],
+            )?,
+            container_capability_info: podman.bounded_gauge_vec(
+                "podman_container_capability_info",
+                "Capability added to or dropped from the container",
+                &["pod", "container", "capability", "added"],
+            )?,
+            pod_by_state: podman.gauge_vec(
+                "podman_pod_by_state",
+                "Count of pods by state",
+                &["state"],
+            )?,
+            secret_total: podman.gauge("podman_secret_total", "Count of secrets defined in Podman")?,
+            system_images_size: podman.gauge(
+                "podman_system_images_size_bytes",
+                "Disk space used by images (bytes)",
+            )?,
+            system_containers_size: podman.gauge(
+                "podman_system_containers_size_bytes",
+                "Disk space used by container writable layers (bytes)",
+            )?,
+            system_volumes_size: podman.gauge(
+                "podman_system_volumes_size_bytes",
+                "Disk space used by volumes (bytes)",
+            )?,
+            system_total_size: podman.gauge(
+                "podman_system_total_size_bytes",
+                "Disk space used by images, containers and volumes (bytes)",
+            )?,
+            container_vecs: podman.container_vecs.take(),
+        })
+    }
+
+    /// Drops the series of the containers that are no longer listed, so that removed containers
+    /// don't count against `--max-label-combinations`. `containers` are `(pod, container)` pairs.
+    pub fn retain_containers(&self, containers: &[(&str, &str)]) {
+        let prefixes: Vec<Vec<&str>> = containers
+            .iter()
+            .map(|(pod, container)| vec![*pod, *container])
+            .collect();
+        for vec in self.container_vecs.iter() {
+            vec.retain_prefixes(&prefixes);
+        }
+    }
+}
+
+/// Registers metrics in one registry. The per-container vecs are bounded by
+/// `--max-label-combinations`.
+struct Builder<'a> {
+    registry: Registry,
+    config: &'a MetricsConfig,
+    label_limit_exceeded: Counter,
+    container_vecs: RefCell<Vec<ContainerVec>>,
+}
+
+impl<'a> Builder<'a> {
+    fn gauge(&self, name: &str, help: &str) -> Result<Gauge> {
+        let gauge = Gauge::new(name, help)?;
+        self.registry.register(Box::new(gauge.clone()))?;
+        Ok(gauge)
+    }
+
+    fn counter(&self, name: &str, help: &str) -> Result<Counter> {
+        let counter = Counter::new(name, help)?;
+        self.registry.register(Box::new(counter.clone()))?;
+        Ok(counter)
+    }
+
+    fn gauge_vec(&self, name: &str, help: &str, label_names: &[&str]) -> Result<GaugeVec> {
+        let vec = GaugeVec::new(Opts::new(name, help), label_names)?;
+        self.registry.register(Box::new(vec.clone()))?;
+        Ok(vec)
+    }
+
+    fn bounded_gauge_vec(
+        &self,
+        name: &str,
+        help: &str,
+        label_names: &[&str],
+    ) -> Result<BoundedGaugeVec> {
+        let per_container = is_per_container(label_names);
+        let (skip_label, label_names) = self.label_names(label_names);
+        let vec = BoundedGaugeVec::new(
+            self.gauge_vec(name, help, &label_names)?,
+            skip_label,
+            self.config.max_label_combinations,
+            self.label_limit_exceeded.clone(),
+            Gauge::new("discarded", "Label combination over the limit")?,
+        );
+        if per_container {
+            let handle = ContainerVec::Gauge(vec.clone());
+            self.container_vecs.borrow_mut().push(handle);
+        }
+        Ok(vec)
+    }
+
+    fn bounded_counter_vec(
+        &self,
+        name: &str,
+        help: &str,
+        label_names: &[&str],
+    ) -> Result<BoundedCounterVec> {
+        let per_container = is_per_container(label_names);
+        let (skip_label, label_names) = self.label_names(label_names);
+        let vec = CounterVec::new(Opts::new(name, help), &label_names)?;
+        self.registry.register(Box::new(vec.clone()))?;
+        let vec = BoundedCounterVec::new(
+            vec,
+            skip_label,
+            self.config.max_label_combinations,
+            self.label_limit_exceeded.clone(),
+            Counter::new("discarded", "Label combination over the limit")?,
+        );
+        if per_container {
+            let handle = ContainerVec::Counter(vec.clone());
+            self.container_vecs.borrow_mut().push(handle);
+        }
+        Ok(vec)
+    }
+
+    /// With `--no-pod-label` the per-container metrics are registered without the `pod` label.
+    /// Returns the index of the dropped label and the remaining label names.
+    fn label_names<'l>(&self, label_names: &[&'l str]) -> (Option<usize>, Vec<&'l str>) {
+        let skip_label = if self.config.no_pod_label && label_names.contains(&"container") {
+            label_names.iter().position(|l| *l == "pod")
+        } else {
+            None
+        };
+        let label_names = label_names
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| Some(*i) != skip_label)
+            .map(|(_, l)| *l)
+            .collect();
+        (skip_label, label_names)
+    }
+}
+
+fn is_per_container(label_names: &[&str]) -> bool {
+    label_names.starts_with(&["pod", "container"])
+}
+
+/// Names of the metrics that `promtool check metrics` flags, renamed by `--metric-naming strict`:
+/// the unit suffix is added, and gauges lose the `_total` suffix reserved for counters.
+pub const STRICT_NAMES: [(&str, &str); 17] = [
+    ("podman_container_total", "podman_containers"),
+    (
+        "podman_container_name_collisions_total",
+        "podman_container_name_collisions",
+    ),
+    ("podman_containers_cpu_total", "podman_containers_cpu"),
+    (
+        "podman_containers_mem_usage_bytes_total",
+        "podman_containers_memory_usage_bytes",
+    ),
+    (
+        "podman_container_uptime",
+        "podman_container_uptime_nanoseconds",
+    ),
+    (
+        "podman_container_uptime_calc",
+        "podman_container_uptime_calc_seconds",
+    ),
+    (
+        "podman_container_system_nano",
+        "podman_container_system_nanoseconds",
+    ),
+    (
+        "podman_container_cpu_nano",
+        "podman_container_cpu_nanoseconds",
+    ),
+    (
+        "podman_container_cpu_system_nano",
+        "podman_container_cpu_system_nanoseconds",
+    ),
+    (
+        "podman_container_mem_usage",
+        "podman_container_memory_usage_bytes",
+    ),
+    (
+        "podman_container_mem_limit",
+        "podman_container_memory_limit_bytes",
+    ),
+    (
+        "podman_container_network_input",
+        "podman_container_network_input_bytes",
+    ),
+    (
+        "podman_container_network_output",
+        "podman_container_network_output_bytes",
+    ),
+    (
+        "podman_container_block_input",
+        "podman_container_block_input_bytes",
+    ),
+    (
+        "podman_container_block_output",
+        "podman_container_block_output_bytes",
+    ),
+    ("podman_pod_mem_usage", "podman_pod_memory_usage_bytes"),
+    ("podman_secret_total", "podman_secrets"),
+];
+
+pub fn strict_name(name: &str) -> Option<&'static str> {
+    STRICT_NAMES
+        .iter()
+        .find(|(legacy, _)| *legacy == name)
+        .map(|(_, strict)| *strict)
+}
+
+/// Replaces the characters not allowed in Prometheus label names with underscores.
+pub fn sanitize_label_name(name: &str) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if sanitized.starts_with(|c: char| c.is_ascii_digit()) {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+/// Environment variables are prefixed with `env_` so that they can't clash with the container
+/// labels.
+fn container_info_labels(expose_labels: &[String], expose_env: &[String]) -> Vec<String> {
+    let mut labels: Vec<String> = [
+        "pod",
+        "container",
+        "id",
+        "image",
+        "image_id",
+        "image_digest",
+        "user",
+        "network_mode",
+        "user_namespace",
+        "cgroup_version",
+        "compose_project",
+        "compose_service",
+        "is_infra",
+    ]
+    .iter()
+    .map(|l| l.to_string())
+    .collect();
+    labels.extend(expose_labels.iter().map(|key| sanitize_label_name(key)));
+    labels.extend(
+        expose_env
+            .iter()
+            .map(|var| format!("env_{}", sanitize_label_name(var))),
+    );
+    labels
+}
+
+/// A label can't be in `podman_container_info` twice, which happens when exposed labels or
+/// environment variables are sanitized to the same name or to the name of a built-in label.
+pub fn check_info_labels(expose_labels: &[String], expose_env: &[String]) -> anyhow::Result<()> {
+    let labels = container_info_labels(expose_labels, expose_env);
+    for (i, label) in labels.iter().enumerate() {
+        if labels[..i].contains(label) {
+            return Err(anyhow::anyhow!(
+                "The exposed labels and env vars add the podman_container_info label {} twice",
+                label
+            ));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,153 @@
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+use prometheus::{Encoder, Result};
+use std::io::Write;
+
+pub const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Encodes metric families in the OpenMetrics text exposition format.
+#[derive(Debug, Default)]
+pub struct OpenMetricsEncoder;
+
+impl OpenMetricsEncoder {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Encoder for OpenMetricsEncoder {
+    fn encode<W: Write>(&self, families: &[MetricFamily], writer: &mut W) -> Result<()> {
+        for family in families {
+            let metric_type = family.get_field_type();
+            let name = match metric_type {
+                MetricType::COUNTER => family
+                    .get_name()
+                    .strip_suffix("_total")
+                    .unwrap_or(family.get_name()),
+                _ => family.get_name(),
+            };
+            let type_name = match metric_type {
+                MetricType::COUNTER => "counter",
+                MetricType::GAUGE => "gauge",
+                MetricType::HISTOGRAM => "histogram",
+                MetricType::SUMMARY => "summary",
+                MetricType::UNTYPED => "unknown",
+            };
+            writeln!(writer, "# TYPE {} {}", name, type_name)?;
+            if !family.get_help().is_empty() {
+                writeln!(writer, "# HELP {} {}", name, escape(family.get_help()))?;
+            }
+
+            for metric in family.get_metric() {
+                match metric_type {
+                    MetricType::COUNTER => {
+                        let value = metric.get_counter().get_value();
+                        write_sample(writer, name, "_total", metric, None, value)?;
+                    }
+                    MetricType::GAUGE => {
+                        let value = metric.get_gauge().get_value();
+                        write_sample(writer, name, "", metric, None, value)?;
+                    }
+                    MetricType::UNTYPED => {
+                        let value = metric.get_untyped().get_value();
+                        write_sample(writer, name, "", metric, None, value)?;
+                    }
+                    MetricType::HISTOGRAM => {
+                        let histogram = metric.get_histogram();
+                        let mut inf_seen = false;
+                        for bucket in histogram.get_bucket() {
+                            let bound = bucket.get_upper_bound();
+                            inf_seen |= bound.is_infinite() && bound.is_sign_positive();
+                            let le = Some(("le", format_value(bound)));
+                            let count = bucket.get_cumulative_count() as f64;
+                            write_sample(writer, name, "_bucket", metric, le, count)?;
+                        }
+                        let count = histogram.get_sample_count() as f64;
+                        if !inf_seen {
+                            let le = Some(("le", "+Inf".to_string()));
+                            write_sample(writer, name, "_bucket", metric, le, count)?;
+                        }
+                        let sum = histogram.get_sample_sum();
+                        write_sample(writer, name, "_sum", metric, None, sum)?;
+                        write_sample(writer, name, "_count", metric, None, count)?;
+                    }
+                    MetricType::SUMMARY => {
+                        let summary = metric.get_summary();
+                        for quantile in summary.get_quantile() {
+                            let label = Some(("quantile", format_value(quantile.get_quantile())));
+                            write_sample(writer, name, "", metric, label, quantile.get_value())?;
+                        }
+                        let sum = summary.get_sample_sum();
+                        let count = summary.get_sample_count() as f64;
+                        write_sample(writer, name, "_sum", metric, None, sum)?;
+                        write_sample(writer, name, "_count", metric, None, count)?;
+                    }
+                }
+            }
+        }
+        writeln!(writer, "# EOF")?;
+        Ok(())
+    }
+
+    fn format_type(&self) -> &str {
+        OPENMETRICS_FORMAT
+    }
+}
+
+fn write_sample<W: Write>(
+    writer: &mut W,
+    name: &str,
+    suffix: &str,
+    metric: &Metric,
+    extra_label: Option<(&str, String)>,
+    value: f64,
+) -> Result<()> {
+    write!(writer, "{}{}", name, suffix)?;
+    write_labels(writer, metric.get_label(), extra_label)?;
+    write!(writer, " {}", format_value(value))?;
+    let timestamp_ms = metric.get_timestamp_ms();
+    if timestamp_ms != 0 {
+        write!(writer, " {}", timestamp_ms as f64 / 1000.0)?;
+    }
+    writeln!(writer)?;
+    Ok(())
+}
+
+fn write_labels<W: Write>(
+    writer: &mut W,
+    labels: &[LabelPair],
+    extra_label: Option<(&str, String)>,
+) -> Result<()> {
+    if labels.is_empty() && extra_label.is_none() {
+        return Ok(());
+    }
+    let pairs = labels
+        .iter()
+        .map(|l| (l.get_name(), escape(l.get_value())))
+        .chain(extra_label.map(|(name, value)| (name, escape(&value))));
+    let mut separator = "{";
+    for (name, value) in pairs {
+        write!(writer, "{}{}=\"{}\"", separator, name, value)?;
+        separator = ",";
+    }
+    write!(writer, "}}")?;
+    Ok(())
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() && value.is_sign_positive() {
+        "+Inf".to_string()
+    } else if value.is_infinite() {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('"', "\\\"")
+}
@@ -0,0 +1,184 @@
+use anyhow::{anyhow, Result};
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct RemoteWriteOptions {
+    pub url: String,
+    pub timeout: Duration,
+    pub user: Option<String>,
+    pub password: Option<String>,
+}
+
+/// Pushes the gathered metrics to a Prometheus remote write endpoint.
+pub struct RemoteWriter {
+    client: reqwest::Client,
+    options: RemoteWriteOptions,
+}
+
+impl RemoteWriter {
+    pub fn new(options: RemoteWriteOptions) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(options.timeout)
+            .build()
+            .map_err(|e| anyhow!("Remote write client: {}", e))?;
+        Ok(Self { client, options })
+    }
+
+    pub async fn send(&self, families: &[MetricFamily], timestamp_ms: i64) -> Result<()> {
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&write_request(families, timestamp_ms))
+            .map_err(|e| anyhow!("Remote write compression: {}", e))?;
+        let mut request = self
+            .client
+            .post(&self.options.url)
+            .header("Content-Encoding", "snappy")
+            .header("Content-Type", "application/x-protobuf")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .body(body);
+        if let Some(user) = &self.options.user {
+            request = request.basic_auth(user, self.options.password.as_ref());
+        }
+        let resp = request
+            .send()
+            .await
+            .map_err(|e| anyhow!("Remote write request: {}", e))?;
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            return Err(anyhow!("Remote write request: {} {}", status, body.trim()));
+        }
+        Ok(())
+    }
+}
+
+/// A sample of the remote write `TimeSeries`, its labels include the `__name__`.
+struct Series {
+    labels: Vec<(String, String)>,
+    value: f64,
+    timestamp_ms: i64,
+}
+
+/// Splits histograms and summaries into their `_bucket`, `_sum` and `_count` series like the
+/// text format does. Metrics without a timestamp of their own get `timestamp_ms`.
+fn series(family: &MetricFamily, timestamp_ms: i64) -> Vec<Series> {
+    let name = family.get_name();
+    let mut series = Vec::new();
+    for metric in family.get_metric() {
+        let timestamp_ms = match metric.get_timestamp_ms() {
+            0 => timestamp_ms,
+            t => t,
+        };
+        let mut push = |suffix: &str, extra_label: Option<(&str, String)>, value: f64| {
+            let mut labels = labels(metric, format!("{}{}", name, suffix));
+            if let Some((label, label_value)) = extra_label {
+                labels.push((label.to_string(), label_value));
+            }
+            labels.sort();
+            series.push(Series {
+                labels,
+                value,
+                timestamp_ms,
+            });
+        };
+        match family.get_field_type() {
+            MetricType::COUNTER => push("", None, metric.get_counter().get_value()),
+            MetricType::GAUGE => push("", None, metric.get_gauge().get_value()),
+            MetricType::UNTYPED => push("", None, metric.get_untyped().get_value()),
+            MetricType::HISTOGRAM => {
+                let histogram = metric.get_histogram();
+                let count = histogram.get_sample_count() as f64;
+                for bucket in histogram.get_bucket() {
+                    let le = Some(("le", format_bound(bucket.get_upper_bound())));
+                    push("_bucket", le, bucket.get_cumulative_count() as f64);
+                }
+                if !histogram
+                    .get_bucket()
+                    .iter()
+                    .any(|b| b.get_upper_bound() == f64::INFINITY)
+                {
+                    push("_bucket", Some(("le", "+Inf".to_string())), count);
+                }
+                push("_sum", None, histogram.get_sample_sum());
+                push("_count", None, count);
+            }
+            MetricType::SUMMARY => {
+                let summary = metric.get_summary();
+                for quantile in summary.get_quantile() {
+                    let label = Some(("quantile", quantile.get_quantile().to_string()));
+                    push("", label, quantile.get_value());
+                }
+                push("_sum", None, summary.get_sample_sum());
+                push("_count", None, summary.get_sample_count() as f64);
+            }
+        }
+    }
+    series
+}
+
+fn labels(metric: &Metric, name: String) -> Vec<(String, String)> {
+    let mut labels = vec![("__name__".to_string(), name)];
+    labels.extend(
+        metric
+            .get_label()
+            .iter()
+            .map(|l| (l.get_name().to_string(), l.get_value().to_string())),
+    );
+    labels
+}
+
+fn format_bound(bound: f64) -> String {
+    if bound == f64::INFINITY {
+        "+Inf".to_string()
+    } else {
+        bound.to_string()
+    }
+}
+
+/// Encodes the `prometheus.WriteRequest` protobuf message by hand, it only needs the
+/// `timeseries` field:
+///
+/// ```text
+/// WriteRequest { repeated TimeSeries timeseries = 1; }
+/// TimeSeries { repeated Label labels = 1; repeated Sample samples = 2; }
+/// Label { string name = 1; string value = 2; }
+/// Sample { double value = 1; int64 timestamp = 2; }
+/// ```
+fn write_request(families: &[MetricFamily], timestamp_ms: i64) -> Vec<u8> {
+    let mut request = Vec::new();
+    for series in families.iter().flat_map(|f| series(f, timestamp_ms)) {
+        let mut time_series = Vec::new();
+        for (name, value) in series.labels.iter() {
+            let mut label = Vec::new();
+            write_bytes(&mut label, 1, name.as_bytes());
+            write_bytes(&mut label, 2, value.as_bytes());
+            write_bytes(&mut time_series, 1, &label);
+        }
+        let mut sample = Vec::new();
+        write_key(&mut sample, 1, 1);
+        sample.extend_from_slice(&series.value.to_le_bytes());
+        write_key(&mut sample, 2, 0);
+        write_varint(&mut sample, series.timestamp_ms as u64);
+        write_bytes(&mut time_series, 2, &sample);
+        write_bytes(&mut request, 1, &time_series);
+    }
+    request
+}
+
+fn write_key(buf: &mut Vec<u8>, field: u64, wire_type: u64) {
+    write_varint(buf, (field << 3) | wire_type);
+}
+
+fn write_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    write_key(buf, field, 2);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
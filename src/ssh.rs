@@ -0,0 +1,221 @@
+use anyhow::{anyhow, Result};
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Set for the `ssh` child so that it runs the exporter itself as `SSH_ASKPASS`.
+pub const ASKPASS_FILE_ENV: &str = "PODMAN_EXPORTER_ASKPASS_FILE";
+
+const TUNNEL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Numbers the tunnels of this process, a reconnect opens the new tunnel while the old one is
+/// still alive.
+static TUNNEL_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Default)]
+pub struct SshOptions {
+    pub identity: Option<PathBuf>,
+    pub passphrase_file: Option<PathBuf>,
+}
+
+/// `ssh://[user@]host[:port]/path/to/podman.sock`
+#[derive(Debug)]
+struct SshUri<'a> {
+    destination: &'a str,
+    port: Option<&'a str>,
+    socket: &'a str,
+}
+
+impl<'a> SshUri<'a> {
+    fn parse(uri: &'a str) -> Result<Self> {
+        let rest = uri
+            .strip_prefix("ssh://")
+            .ok_or_else(|| anyhow!("Not an ssh:// URI: {}", uri))?;
+        let (authority, socket) = match rest.find('/') {
+            Some(i) if i + 1 < rest.len() => (&rest[..i], &rest[i..]),
+            _ => return Err(anyhow!("Missing remote socket path in {}", uri)),
+        };
+        // A trailing `:port` after the host, IPv6 hosts have to be in brackets.
+        let (destination, port) = match authority.rsplit_once(':') {
+            Some((destination, port)) if !port.contains(']') => (destination, Some(port)),
+            _ => (authority, None),
+        };
+        if destination.is_empty() || destination.ends_with('@') {
+            return Err(anyhow!("Missing host in {}", uri));
+        }
+        Ok(Self {
+            destination,
+            port,
+            socket,
+        })
+    }
+}
+
+/// Forwards a local unix socket to the remote Podman socket with the system `ssh` client, since
+/// `podman_api` has no SSH transport.
+pub struct SshTunnel {
+    child: Child,
+    socket: PathBuf,
+}
+
+impl SshTunnel {
+    pub fn open(uri: &str, options: &SshOptions) -> Result<Self> {
+        let remote = SshUri::parse(uri)?;
+        // ssh would only report a failed authentication, so check the files up front.
+        for path in [&options.identity, &options.passphrase_file]
+            .into_iter()
+            .flatten()
+        {
+            fs::File::open(path).map_err(|e| anyhow!("Read {}: {}", path.display(), e))?;
+        }
+        let socket = env::temp_dir().join(format!(
+            "podman-exporter-{}-{}.sock",
+            process::id(),
+            TUNNEL_COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_file(&socket);
+
+        let mut cmd = Command::new("ssh");
+        cmd.args(["-N", "-T", "-o", "ExitOnForwardFailure=yes"])
+            .args(["-o", "StreamLocalBindUnlink=yes"])
+            .arg("-L")
+            .arg(format!("{}:{}", socket.display(), remote.socket));
+        if let Some(port) = remote.port {
+            cmd.args(["-p", port]);
+        }
+        if let Some(identity) = &options.identity {
+            cmd.arg("-i")
+                .arg(identity)
+                .args(["-o", "IdentitiesOnly=yes"]);
+        }
+        match &options.passphrase_file {
+            Some(path) => {
+                let exe = env::current_exe().map_err(|e| anyhow!("Locate executable: {}", e))?;
+                cmd.env("SSH_ASKPASS", exe)
+                    .env("SSH_ASKPASS_REQUIRE", "force")
+                    .env(ASKPASS_FILE_ENV, path);
+                if env::var_os("DISPLAY").is_none() {
+                    cmd.env("DISPLAY", ":0");
+                }
+            }
+            None => {
+                cmd.args(["-o", "BatchMode=yes"]);
+            }
+        }
+        let mut child = cmd
+            .arg(remote.destination)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow!("Start ssh: {}", e))?;
+        if let Some(stderr) = child.stderr.take() {
+            thread::spawn(move || {
+                for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                    warn!(output = %line, "ssh");
+                }
+            });
+        }
+        let mut tunnel = Self { child, socket };
+        tunnel.wait_ready()?;
+        Ok(tunnel)
+    }
+
+    pub fn uri(&self) -> String {
+        format!("unix://{}", self.socket.display())
+    }
+
+    fn wait_ready(&mut self) -> Result<()> {
+        let started = Instant::now();
+        while !self.socket.exists() {
+            if let Some(status) = self.child.try_wait()? {
+                return Err(anyhow!("ssh exited with {}", status));
+            }
+            if started.elapsed() > TUNNEL_TIMEOUT {
+                return Err(anyhow!("ssh tunnel not ready after {:?}", TUNNEL_TIMEOUT));
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SshTunnel {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_file(&self.socket);
+    }
+}
+
+/// Prints the key passphrase for `ssh` when the exporter runs as its `SSH_ASKPASS` program.
+pub fn askpass(path: &Path) -> ! {
+    match fs::read_to_string(path) {
+        Ok(passphrase) => {
+            println!("{}", passphrase.trim_end_matches(['\r', '\n']));
+            process::exit(0)
+        }
+        Err(err) => {
+            eprintln!("Read passphrase file {}: {}", path.display(), err);
+            process::exit(1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(uri: &str) -> (&str, Option<&str>, &str) {
+        let remote = SshUri::parse(uri).unwrap();
+        (remote.destination, remote.port, remote.socket)
+    }
+
+    #[test]
+    fn parse_ssh_uri() {
+        assert_eq!(
+            parse("ssh://core@host/run/podman/podman.sock"),
+            ("core@host", None, "/run/podman/podman.sock")
+        );
+        assert_eq!(
+            parse("ssh://core@host:2222/run/user/1000/podman/podman.sock"),
+            (
+                "core@host",
+                Some("2222"),
+                "/run/user/1000/podman/podman.sock"
+            )
+        );
+        assert_eq!(
+            parse("ssh://host/podman.sock"),
+            ("host", None, "/podman.sock")
+        );
+        assert_eq!(
+            parse("ssh://core@[::1]/podman.sock"),
+            ("core@[::1]", None, "/podman.sock")
+        );
+        assert_eq!(
+            parse("ssh://[::1]:22/podman.sock"),
+            ("[::1]", Some("22"), "/podman.sock")
+        );
+    }
+
+    #[test]
+    fn parse_invalid_ssh_uri() {
+        for uri in [
+            "unix:///run/podman/podman.sock",
+            "ssh://host",
+            "ssh://host/",
+            "ssh:///podman.sock",
+            "ssh://core@/podman.sock",
+            "ssh://:22/podman.sock",
+        ] {
+            assert!(SshUri::parse(uri).is_err(), "{}", uri);
+        }
+    }
+}
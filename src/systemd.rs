@@ -0,0 +1,86 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+/// Asks systemd over DBus for the `ActiveState` of units. The user manager is used when the
+/// exporter has a session bus (e.g. when it runs as a user unit next to rootless Podman),
+/// otherwise the system manager.
+#[cfg(feature = "systemd")]
+#[derive(Clone)]
+pub struct SystemdClient {
+    connection: zbus::Connection,
+}
+
+#[cfg(feature = "systemd")]
+impl SystemdClient {
+    pub async fn connect() -> Result<Self> {
+        let connection = if std::env::var_os("DBUS_SESSION_BUS_ADDRESS").is_some() {
+            zbus::Connection::session().await
+        } else {
+            zbus::Connection::system().await
+        }
+        .map_err(|e| anyhow!("DBus connection: {}", e))?;
+        Ok(Self { connection })
+    }
+
+    /// Active states by unit name, units that systemd doesn't know are left out.
+    pub async fn unit_states(&self, names: &[String]) -> Result<HashMap<String, String>> {
+        type Unit = (
+            String,
+            String,
+            String,
+            String,
+            String,
+            String,
+            zbus::zvariant::OwnedObjectPath,
+            u32,
+            String,
+            zbus::zvariant::OwnedObjectPath,
+        );
+        let reply = self
+            .connection
+            .call_method(
+                Some("org.freedesktop.systemd1"),
+                "/org/freedesktop/systemd1",
+                Some("org.freedesktop.systemd1.Manager"),
+                "ListUnitsByNames",
+                &(names,),
+            )
+            .await
+            .map_err(|e| anyhow!("ListUnitsByNames: {}", e))?;
+        let units: Vec<Unit> = reply
+            .body()
+            .deserialize()
+            .map_err(|e| anyhow!("ListUnitsByNames reply: {}", e))?;
+        Ok(units
+            .into_iter()
+            .filter(|(_, _, load_state, ..)| load_state != "not-found")
+            .map(|(name, _, _, active_state, ..)| (name, active_state))
+            .collect())
+    }
+}
+
+#[cfg(not(feature = "systemd"))]
+#[derive(Clone)]
+pub struct SystemdClient;
+
+#[cfg(not(feature = "systemd"))]
+impl SystemdClient {
+    pub async fn connect() -> Result<Self> {
+        Err(anyhow!(
+            "systemd units require building with `--features systemd`"
+        ))
+    }
+
+    pub async fn unit_states(&self, _names: &[String]) -> Result<HashMap<String, String>> {
+        Ok(HashMap::new())
+    }
+}
+
+/// `1` for active units, `-1` for failed ones and `0` for all other states.
+pub fn active_state_value(state: &str) -> f64 {
+    match state {
+        "active" | "reloading" => 1.0,
+        "failed" => -1.0,
+        _ => 0.0,
+    }
+}
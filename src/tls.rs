@@ -0,0 +1,65 @@
+use anyhow::{anyhow, Result};
+use podman_api::Podman;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    pub ca: Option<PathBuf>,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+impl TlsOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.ca.is_some() || self.cert.is_some() || self.key.is_some()
+    }
+}
+
+/// `podman_api` reads `cert.pem`, `key.pem` and `ca.pem` from a single directory, so the
+/// configured files are linked into a private one while the connector loads them. Without a CA
+/// the server certificate is verified against the system trust store.
+#[cfg(feature = "tls")]
+pub fn connect(host: &str, options: &TlsOptions) -> Result<Podman> {
+    use std::fs::{self, DirBuilder};
+    use std::os::unix::fs::{symlink, DirBuilderExt};
+    use std::{env, process};
+
+    let (cert, key) = match (&options.cert, &options.key) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => {
+            return Err(anyhow!(
+                "TLS needs both --podman-tcp-tls-cert and --podman-tcp-tls-key"
+            ))
+        }
+    };
+    let dir = env::temp_dir().join(format!("podman-exporter-{}-tls", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .map_err(|e| anyhow!("Create {}: {}", dir.display(), e))?;
+    let files = [
+        ("cert.pem", Some(cert)),
+        ("key.pem", Some(key)),
+        ("ca.pem", options.ca.as_ref()),
+    ];
+    let linked = files
+        .into_iter()
+        .filter_map(|(name, path)| path.map(|path| (name, path)))
+        .try_for_each(|(name, path)| {
+            let path = fs::canonicalize(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+            symlink(&path, dir.join(name)).map_err(|e| anyhow!("Link {}: {}", path.display(), e))
+        });
+    let podman = linked.and_then(|_| {
+        Podman::tls(host, &dir, options.ca.is_some()).map_err(|e| anyhow!("TLS setup: {}", e))
+    });
+    let _ = fs::remove_dir_all(&dir);
+    podman
+}
+
+#[cfg(not(feature = "tls"))]
+pub fn connect(_host: &str, _options: &TlsOptions) -> Result<Podman> {
+    Err(anyhow!(
+        "TLS to the Podman API requires building with `--features tls`"
+    ))
+}